@@ -0,0 +1,111 @@
+//! Startup pass that backs up jobs whose paths changed while the daemon was
+//! not running.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::job::{Context, Job, Trigger};
+use crate::shutdown::Phase;
+use crate::snapshots;
+
+/// A job's `catch-up` setting: `true`, or a mapping with `max-age` and `mtime`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "Value")]
+pub struct CatchUp {
+    pub enabled: bool,
+    /// Back up when the latest snapshot is older than this.
+    pub max_age: Option<Duration>,
+    /// Back up when a file or directory was modified after the latest snapshot.
+    pub mtime: bool,
+}
+
+impl TryFrom<Value> for CatchUp {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<CatchUp, String> {
+        let map = match value {
+            Value::Bool(enabled) => return Ok(CatchUp { enabled, max_age: None, mtime: true }),
+            Value::Mapping(map) => map,
+            _ => return Err("expected true, false or a mapping with max-age and mtime".to_owned()),
+        };
+        let mut catch_up = CatchUp { enabled: true, max_age: None, mtime: true };
+        for (key, value) in map {
+            match (key.as_str(), value) {
+                (Some("max-age"), Value::String(age)) => {
+                    let age = humantime::parse_duration(&age).map_err(|e| format!("invalid max-age '{}': {}", age, e))?;
+                    catch_up.max_age = Some(age);
+                }
+                (Some("mtime"), Value::Bool(mtime)) => catch_up.mtime = mtime,
+                (Some("max-age"), _) => return Err("max-age must be a duration such as 1d".to_owned()),
+                (Some("mtime"), _) => return Err("mtime must be true or false".to_owned()),
+                (key, _) => return Err(format!("unknown key {:?}, expected max-age or mtime", key.unwrap_or_default())),
+            }
+        }
+        Ok(catch_up)
+    }
+}
+
+/// Checks the job's latest snapshot in each repository and runs a backup
+/// through the normal pipeline if any repository is behind. The snapshot
+/// time comes from the job's saved state, or from restic when there is none.
+pub async fn run(job: Arc<Job>, ctx: Context) {
+    let config = &job.config;
+    let Some(catch_up) = config.catch_up.as_ref() else { return };
+    let state = ctx.state.job(&config.name);
+    let mut reason = None;
+    for target in &config.targets {
+        let saved = state.targets.get(&target.name).and_then(|t| t.last_snapshot_time);
+        let taken = match saved {
+            Some(time) => UNIX_EPOCH + Duration::from_secs(time),
+            None => match snapshots::latest(config, target).await {
+                Ok(Some(snapshot)) => snapshot.time.into(),
+                Ok(None) => {
+                    reason = Some(format!("{} has no snapshot yet", target.name));
+                    break;
+                }
+                Err(e) => {
+                    warn!("{} Catch-up: unable to list snapshots in {}: {}", config.name, target.name, e);
+                    continue;
+                }
+            },
+        };
+        let age = SystemTime::now().duration_since(taken).unwrap_or_default();
+        if catch_up.max_age.is_some_and(|max| age > max) {
+            reason = Some(format!("latest snapshot in {} is {} old", target.name, humantime::format_duration(Duration::from_secs(age.as_secs()))));
+            break;
+        }
+        if catch_up.mtime {
+            let paths = config.paths.clone();
+            let changed = tokio::task::spawn_blocking(move || paths.iter().find_map(|p| modified_after(Path::new(p), taken))).await.ok().flatten();
+            if let Some(path) = changed {
+                reason = Some(format!("{} changed after the latest snapshot in {}", path, target.name));
+                break;
+            }
+        }
+    }
+    match reason {
+        Some(reason) if ctx.shutdown.phase() == Phase::Running => {
+            info!("{} Catch-up: {}, backing up now.", config.name, reason);
+            let _ = job.run(Trigger::CatchUp, &ctx).await;
+        }
+        Some(_) => {}
+        None => info!("{} Catch-up: nothing changed since the latest snapshot.", config.name),
+    }
+}
+
+/// The first file or directory under `path` modified after `since`.
+/// Symbolic links are not followed.
+fn modified_after(path: &Path, since: SystemTime) -> Option<String> {
+    let meta = std::fs::symlink_metadata(path).ok()?;
+    if meta.modified().is_ok_and(|m| m > since) {
+        return Some(path.display().to_string());
+    }
+    if !meta.is_dir() {
+        return None;
+    }
+    std::fs::read_dir(path).ok()?.filter_map(Result::ok).find_map(|entry| modified_after(&entry.path(), since))
+}
@@ -0,0 +1,192 @@
+//! `check-config` subcommand: validates everything the daemon depends on
+//! without backing anything up.
+
+use std::fmt::Display;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use notify::{RecursiveMode, Watcher};
+
+use crate::config::{BackupConfig, Config};
+use crate::init;
+use crate::restic::{self, BackupError};
+use crate::secrets::{self, PasswordSource};
+
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn pass(&mut self, item: &str, detail: impl Display) {
+        println!("[PASS] {}: {}", item, detail);
+    }
+
+    fn fail(&mut self, item: &str, detail: impl Display) {
+        self.failures += 1;
+        println!("[FAIL] {}: {}", item, detail);
+    }
+
+    fn record(&mut self, item: &str, result: Result<String, String>) {
+        match result {
+            Ok(detail) => self.pass(item, detail),
+            Err(detail) => self.fail(item, detail),
+        }
+    }
+}
+
+/// Runs every check and prints a per-item report. Returns whether all passed.
+pub fn run(config: &Config) -> bool {
+    let mut report = Report { failures: 0 };
+
+    // Labelled by index like config problems, so both point at the same entry.
+    for (i, job) in config.dirs.iter().enumerate() {
+        for path in &job.paths {
+            let result = if job.watch { check_watchable(path) } else { check_exists(path) };
+            report.record(&format!("dirs[{}].path", i), result);
+        }
+        let mut exclude_files: Vec<&String> = job.targets.iter().filter_map(|t| t.exclude_file.as_ref()).collect();
+        exclude_files.dedup();
+        for exclude_file in exclude_files {
+            report.record(&format!("dirs[{}].exclude-file", i), check_file(exclude_file));
+        }
+    }
+    for backup in config.repositories() {
+        let item = |key: &str| format!("repositories[{}].{}", backup.name, key);
+        report.record(&item("restic-path"), check_executable(backup));
+        match &backup.password {
+            PasswordSource::Command(command) => report.record(&item("password-command"), check_password_command(backup, command)),
+            PasswordSource::File(file) => report.record(&item("password-file"), check_password_file(file)),
+            PasswordSource::Env(var) => report.record(&item("password-env"), check_password_env(var)),
+        }
+        if let Some(tmp) = backup.env.vars.get("TMPDIR") {
+            report.record(&item("tmp-dir"), check_directory(tmp));
+        }
+        report.record(&item("repo"), check_repository(backup));
+    }
+
+    if report.failures == 0 {
+        println!("All checks passed.");
+    } else {
+        println!("{} check(s) failed.", report.failures);
+    }
+    report.failures == 0
+}
+
+/// Resolves `restic-path` the same way the spawned command will, using
+/// `env-path` when set and the default `PATH` when `clear-env` is.
+fn resolve_executable(config: &BackupConfig) -> Option<PathBuf> {
+    let program = Path::new(&config.restic_path);
+    if program.components().count() > 1 {
+        return Some(program.to_path_buf());
+    }
+    let search = config.env.search_path()?;
+    std::env::split_paths(&search).map(|dir| dir.join(program)).find(|p| p.is_file())
+}
+
+fn check_executable(config: &BackupConfig) -> Result<String, String> {
+    let path = resolve_executable(config).ok_or(format!("{} not found in PATH", config.restic_path))?;
+    let meta = std::fs::metadata(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Err(format!("{} is not an executable file", path.display()));
+    }
+    Ok(format!("{} is executable", path.display()))
+}
+
+fn check_file(path: &str) -> Result<String, String> {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_file() => Ok(format!("{} exists", path)),
+        Ok(_) => Err(format!("{} is not a regular file", path)),
+        Err(e) => Err(format!("{}: {}", path, e)),
+    }
+}
+
+fn check_directory(path: &str) -> Result<String, String> {
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => Ok(format!("{} is a directory", path)),
+        Ok(_) => Err(format!("{} is not a directory", path)),
+        Err(e) => Err(format!("{}: {}", path, e)),
+    }
+}
+
+fn check_exists(path: &str) -> Result<String, String> {
+    match Path::new(path).exists() {
+        true => Ok(format!("{} exists", path)),
+        false => Err(format!("{} does not exist", path)),
+    }
+}
+
+fn check_watchable(path: &str) -> Result<String, String> {
+    if !Path::new(path).exists() {
+        return Err(format!("{} does not exist", path));
+    }
+    let mut watcher = notify::recommended_watcher(|_: notify::Result<notify::Event>| {})
+        .map_err(|e| format!("unable to create watcher: {}", e))?;
+    watcher
+        .watch(Path::new(path), RecursiveMode::Recursive)
+        .map_err(|e| format!("unable to watch {}: {}", path, e))?;
+    Ok(format!("{} exists and can be watched", path))
+}
+
+fn check_password_command(config: &BackupConfig, command: &str) -> Result<String, String> {
+    let mut cmd = Command::new("sh");
+    config.env.apply(&mut cmd);
+    // The output is the repository password, so it is never printed.
+    let output = cmd
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("unable to run: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("exited with {}: {}", output.status, first_line(&output.stderr)));
+    }
+    if output.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Err("printed an empty password".to_owned());
+    }
+    Ok("ran successfully".to_owned())
+}
+
+fn check_password_file(path: &str) -> Result<String, String> {
+    check_file(path)?;
+    secrets::check_permissions(path)?;
+    // The content is the repository password, so it is never printed.
+    match std::fs::read(path) {
+        Ok(content) if content.iter().all(u8::is_ascii_whitespace) => Err(format!("{} is empty", path)),
+        Ok(_) => Ok(format!("{} is readable and not accessible to other users", path)),
+        Err(e) => Err(format!("{}: {}", path, e)),
+    }
+}
+
+fn check_password_env(var: &str) -> Result<String, String> {
+    match std::env::var_os(var) {
+        Some(value) if !value.is_empty() => Ok(format!("{} is set", var)),
+        Some(_) => Err(format!("{} is empty", var)),
+        None => Err(format!("{} is not set", var)),
+    }
+}
+
+fn check_repository(config: &BackupConfig) -> Result<String, String> {
+    let output = restic::command(config)
+        .arg("--no-lock")
+        .arg("cat")
+        .arg("config")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("unable to spawn restic: {}", e))?;
+    if !output.status.success() && config.init_if_missing {
+        // The same test the daemon uses, which also covers restic < 0.17.
+        let error = BackupError::Exit { code: output.status.code(), stderr: String::from_utf8_lossy(&output.stderr).into_owned() };
+        if init::is_missing(&error) {
+            return Ok(format!("{} does not exist yet and will be initialized", config.repo));
+        }
+    }
+    if !output.status.success() {
+        return Err(format!("{} is not reachable ({}): {}", config.repo, output.status, first_line(&output.stderr)));
+    }
+    Ok(format!("{} is reachable", config.repo))
+}
+
+fn first_line(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).lines().find(|l| !l.trim().is_empty()).unwrap_or("").trim().to_owned()
+}
@@ -0,0 +1,904 @@
+//! Loading and validation of the YAML configuration file.
+//!
+//! Every key is deserialized on its own so that a single run reports all
+//! problems in the file instead of stopping at the first one.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+use crate::catchup::CatchUp;
+use crate::env::{self, Environment};
+use crate::hooks::Hooks;
+use crate::maintenance::CheckConfig;
+use crate::options::{BackupOptions, Compression};
+use crate::retention::Retention;
+use crate::retry::RetryPolicy;
+use crate::schedule::Schedule;
+use crate::secrets::{self, Credentials, PasswordSource};
+
+const DEFAULT_RESTIC_PATH: &str = "restic";
+const DEFAULT_LOGFILE: &str = "restic-automator.log";
+const DEFAULT_THROTTLE: u64 = 60;
+const DEFAULT_MAX_WAIT: u64 = 3600;
+const DEFAULT_PROGRESS_INTERVAL: u64 = 60;
+const DEFAULT_SHUTDOWN_GRACE: u64 = 600;
+const DEFAULT_STALE_LOCK_AGE: u64 = 3600;
+/// restic refreshes its locks every 5 minutes and itself treats them as
+/// stale after 30, so a shorter age would remove locks still in use.
+const MIN_STALE_LOCK_AGE: u64 = 1800;
+const DEFAULT_MAX_PARALLEL_BACKUPS: usize = 1;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub logfile: String,
+    /// JSON file mirroring the progress of running backups.
+    pub status_file: Option<String>,
+    /// Directory keeping each job's and repository's state across restarts.
+    pub state_dir: Option<String>,
+    /// JSON Lines file every backup run is appended to.
+    pub history_file: Option<String>,
+    /// Seconds running backups may take to finish after SIGINT/SIGTERM.
+    pub shutdown_grace: u64,
+    /// Most backup, check, forget and prune runs at once across all repositories.
+    pub max_restic_processes: Option<usize>,
+    pub dirs: Vec<BackupJobConfig>,
+}
+
+/// Settings for invoking restic against one repository. Keys set on a named
+/// repository take precedence over the job's keys, which take precedence over
+/// the top-level keys.
+#[derive(Clone, Debug)]
+pub struct BackupConfig {
+    /// Name of the entry in `repositories`, or the location for a job's own `repo`.
+    pub name: String,
+    pub repo: String,
+    pub exclude_file: Option<String>,
+    pub password: PasswordSource,
+    /// Backend credentials injected into restic's environment.
+    pub credentials: Credentials,
+    /// Environment restic and the password command run in.
+    pub env: Environment,
+    pub restic_path: String,
+    /// Seconds after which a lock is considered abandoned.
+    pub stale_lock_age: u64,
+    /// Most backups running against this repository at once.
+    pub max_parallel_backups: usize,
+    /// Periodic `restic check` of this repository.
+    pub check: Option<CheckConfig>,
+    /// Run `restic init` when the repository does not exist.
+    pub init_if_missing: bool,
+    /// Repository whose chunker parameters a new repository copies.
+    pub copy_chunker_params: Option<Box<BackupConfig>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct BackupJobConfig {
+    pub name: String,
+    /// Everything backed up together in one snapshot.
+    pub paths: Vec<String>,
+    /// Back up when files under `paths` change.
+    pub watch: bool,
+    /// Back up on a timer, in addition to or instead of watching.
+    pub schedule: Option<Schedule>,
+    /// Back up at startup if the paths changed while the daemon was stopped.
+    pub catch_up: Option<CatchUp>,
+    /// Seconds without filesystem events before a backup starts.
+    pub throttle: u64,
+    /// Longest a backup is postponed by continuous changes, in seconds.
+    pub max_wait: u64,
+    /// Run restic without `-q` and report its status messages.
+    pub progress: bool,
+    /// Seconds between logged progress lines.
+    pub progress_interval: u64,
+    /// Seconds after which a running restic is terminated.
+    pub timeout: Option<u64>,
+    /// Snapshot retention policy applied with `restic forget`.
+    pub retention: Option<Retention>,
+    /// How failed backups are retried; without one they are not.
+    pub retry: Option<RetryPolicy>,
+    /// Options passed to `restic backup`.
+    pub options: BackupOptions,
+    /// Top-level hooks followed by the job's own.
+    pub hooks: Vec<Hooks>,
+    /// Every repository this job backs up to.
+    pub targets: Vec<BackupConfig>,
+}
+
+impl BackupJobConfig {
+    /// The job's paths for log messages.
+    pub fn paths_label(&self) -> String {
+        self.paths.join(", ")
+    }
+}
+
+impl Config {
+    /// Restic settings of the first target using each distinct repository.
+    pub fn repositories(&self) -> Vec<&BackupConfig> {
+        let mut repos: Vec<&BackupConfig> = vec![];
+        for target in self.dirs.iter().flat_map(|job| &job.targets) {
+            if !repos.iter().any(|r| r.repo == target.repo) {
+                repos.push(target);
+            }
+        }
+        repos
+    }
+}
+
+/// A single problem found in the configuration file.
+#[derive(Debug)]
+pub struct Problem {
+    pub key: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// All problems found while loading a configuration file.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: String,
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) in {}:", self.problems.len(), self.file)?;
+        for p in &self.problems {
+            match (p.line, p.key.is_empty()) {
+                (Some(line), false) => write!(f, "\n  {}:{}: {}: {}", self.file, line, p.key, p.message)?,
+                (Some(line), true) => write!(f, "\n  {}:{}: {}", self.file, line, p.message)?,
+                (None, false) => write!(f, "\n  {}: {}: {}", self.file, p.key, p.message)?,
+                (None, true) => write!(f, "\n  {}: {}", self.file, p.message)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        Config::parse(path, &Config::load_raw(path)?)
+    }
+
+    fn load_raw(path: &str) -> Result<String, ConfigError> {
+        std::fs::read_to_string(path).map_err(|e| ConfigError {
+            file: path.to_owned(),
+            problems: vec![Problem { key: String::new(), line: None, message: format!("unable to read file: {}", e) }],
+        })
+    }
+
+    /// Reads only `history-file`, for the `history` subcommand, which must
+    /// work without the secrets and paths a full load checks.
+    pub fn load_history_file(path: &str) -> Result<Option<String>, ConfigError> {
+        let text = Config::load_raw(path)?;
+        let error = |line: Option<usize>, message: String| ConfigError {
+            file: path.to_owned(),
+            problems: vec![Problem { key: "history-file".to_owned(), line, message }],
+        };
+        let value = match serde_yaml::from_str::<Value>(&text) {
+            Ok(Value::Mapping(mut map)) => map.remove("history-file"),
+            Ok(_) => return Err(error(None, "top level must be a mapping".to_owned())),
+            Err(e) => return Err(error(e.location().map(|l| l.line()), e.to_string())),
+        };
+        match value {
+            None => Ok(None),
+            Some(Value::String(file)) => Ok(Some(file)),
+            Some(_) => Err(error(LineIndex::build(&text).get("history-file").copied(), "expected a file path".to_owned())),
+        }
+    }
+
+    pub fn parse(file: &str, text: &str) -> Result<Config, ConfigError> {
+        let mut loader = Loader { lines: LineIndex::build(text), problems: vec![] };
+        let config = match serde_yaml::from_str::<Value>(text) {
+            Ok(Value::Mapping(map)) => loader.config(map),
+            Ok(_) => {
+                loader.problem("", "top level must be a mapping");
+                None
+            }
+            Err(e) => {
+                let line = e.location().map(|l| l.line());
+                loader.problems.push(Problem { key: String::new(), line, message: e.to_string() });
+                None
+            }
+        };
+        match config {
+            Some(config) if loader.problems.is_empty() => Ok(config),
+            _ => Err(ConfigError { file: file.to_owned(), problems: loader.problems }),
+        }
+    }
+}
+
+struct Loader {
+    lines: HashMap<String, usize>,
+    problems: Vec<Problem>,
+}
+
+impl Loader {
+    fn problem(&mut self, key: &str, message: impl Into<String>) {
+        let line = self.lines.get(key).copied();
+        self.problems.push(Problem { key: key.to_owned(), line, message: message.into() });
+    }
+
+    fn config(&mut self, map: Mapping) -> Option<Config> {
+        let mut f = Fields::new(self, map, "");
+        let mut defaults = ResticSettings::read(&mut f);
+        defaults.check = f.optional("check");
+        let logfile = f.optional("logfile").unwrap_or_else(|| DEFAULT_LOGFILE.to_owned());
+        let status_file = f.optional::<String>("status-file");
+        let state_dir = f.optional::<String>("state-dir");
+        let history_file = f.optional::<String>("history-file");
+        let shutdown_grace = f.optional("shutdown-grace").unwrap_or(DEFAULT_SHUTDOWN_GRACE);
+        let max_restic_processes = f.positive("max-restic-processes");
+        let hooks = f.optional::<Hooks>("hooks");
+        let repositories = f.optional::<Mapping>("repositories").unwrap_or_default();
+        let dirs = f.required::<Vec<Value>>("dirs");
+        f.finish();
+
+        let repositories = self.repositories(repositories);
+        let dirs = self.dirs(dirs?, &defaults, &repositories, hooks.as_ref());
+        Some(Config { logfile, status_file, state_dir, history_file, shutdown_grace, max_restic_processes, dirs })
+    }
+
+    fn repositories(&mut self, map: Mapping) -> Vec<(String, ResticSettings)> {
+        let mut repositories = vec![];
+        for (name, value) in map {
+            let name = match name {
+                Value::String(name) => name,
+                other => {
+                    self.problem("repositories", format!("repository name {:?} is not a string", other));
+                    continue;
+                }
+            };
+            let key = format!("repositories.{}", name);
+            let map = match value {
+                Value::Mapping(map) => map,
+                _ => {
+                    self.problem(&key, "expected a mapping with at least repo");
+                    continue;
+                }
+            };
+            let mut f = Fields::new(self, map, &key);
+            let mut settings = ResticSettings::read(&mut f);
+            settings.check = f.optional("check");
+            if settings.repo.is_none() {
+                f.required::<String>("repo");
+            }
+            f.finish();
+            repositories.push((name, settings));
+        }
+        repositories
+    }
+
+    fn dirs(
+        &mut self,
+        items: Vec<Value>,
+        defaults: &ResticSettings,
+        repositories: &[(String, ResticSettings)],
+        hooks: Option<&Hooks>,
+    ) -> Vec<BackupJobConfig> {
+        if items.is_empty() {
+            self.problem("dirs", "at least one entry is required");
+        }
+        let mut jobs: Vec<BackupJobConfig> = vec![];
+        let mut keys = vec![];
+        for (i, item) in items.into_iter().enumerate() {
+            let key = format!("dirs[{}]", i);
+            let map = match item {
+                Value::Mapping(map) => map,
+                _ => {
+                    self.problem(&key, "expected a mapping with name and path");
+                    continue;
+                }
+            };
+            if let Some(job) = self.job(map, &key, defaults, repositories, hooks) {
+                if jobs.iter().any(|j| j.name == job.name) {
+                    self.problem(&format!("{}.name", key), format!("duplicate job name '{}'", job.name));
+                }
+                jobs.push(job);
+                keys.push(key);
+            }
+        }
+        // The backup queue of a repository is shared by every job using it.
+        let mut limits: Vec<(&str, usize, &str)> = vec![];
+        for (job, key) in jobs.iter().zip(&keys) {
+            for target in &job.targets {
+                match limits.iter().find(|(repo, _, _)| *repo == target.repo) {
+                    Some((_, limit, other)) if *limit != target.max_parallel_backups => {
+                        let message = format!(
+                            "max-parallel-backups for {} is {} here but {} in job '{}'; set it once on the repository",
+                            target.name, target.max_parallel_backups, limit, other
+                        );
+                        self.problem(key, message);
+                    }
+                    Some(_) => {}
+                    None => limits.push((&target.repo, target.max_parallel_backups, &job.name)),
+                }
+            }
+        }
+        jobs
+    }
+
+    fn job(
+        &mut self,
+        map: Mapping,
+        prefix: &str,
+        defaults: &ResticSettings,
+        repositories: &[(String, ResticSettings)],
+        global_hooks: Option<&Hooks>,
+    ) -> Option<BackupJobConfig> {
+        let mut f = Fields::new(self, map, prefix);
+        let name = f.required::<String>("name");
+        let paths = f.paths("path");
+        let watch = f.optional("watch").unwrap_or(true);
+        let schedule = f.optional::<Schedule>("schedule");
+        let catch_up = f.optional::<CatchUp>("catch-up").filter(|c| c.enabled);
+        let throttle = f.optional("throttle").unwrap_or(DEFAULT_THROTTLE);
+        let max_wait = f.optional("max-wait").unwrap_or(DEFAULT_MAX_WAIT.max(throttle));
+        let progress = f.optional("progress").unwrap_or(false);
+        let progress_interval = f.optional("progress-interval").unwrap_or(DEFAULT_PROGRESS_INTERVAL);
+        let timeout = f.optional::<u64>("timeout");
+        let retention = f.optional::<Retention>("retention");
+        let retry = f.optional::<RetryPolicy>("retry");
+        let options = BackupOptions {
+            tags: f.optional("tags").or_else(|| name.clone().map(|name| vec![name])).unwrap_or_default(),
+            host: f.optional("host"),
+            exclude: f.optional("exclude").unwrap_or_default(),
+            iexclude: f.optional("iexclude").unwrap_or_default(),
+            exclude_if_present: f.optional("exclude-if-present").unwrap_or_default(),
+            exclude_caches: f.optional("exclude-caches").unwrap_or(false),
+            exclude_larger_than: f.optional("exclude-larger-than"),
+            one_file_system: f.optional("one-file-system").unwrap_or(false),
+            compression: f.optional::<Compression>("compression"),
+            read_concurrency: f.optional("read-concurrency"),
+            pack_size: f.optional("pack-size"),
+            extra_args: f.optional("extra-args").unwrap_or_default(),
+        };
+        let hooks = global_hooks.cloned().into_iter().chain(f.optional::<Hooks>("hooks")).collect();
+        let overrides = ResticSettings::read(&mut f);
+        let names = f.optional::<Vec<String>>("repositories");
+        f.finish();
+        if retention.as_ref().is_some_and(|r| !r.has_policy()) {
+            self.problem(&format!("{}.retention", prefix), "at least one keep-* option is required");
+        }
+        for (key, message) in retry.iter().flat_map(RetryPolicy::problems) {
+            self.problem(&format!("{}.retry.{}", prefix, key), message);
+        }
+        for (key, message) in options.problems() {
+            self.problem(&format!("{}.{}", prefix, key), message);
+        }
+        if !watch && schedule.is_none() {
+            self.problem(prefix, "job neither watches its paths nor has a schedule");
+        }
+        if max_wait < throttle {
+            self.problem(&format!("{}.max-wait", prefix), "must not be shorter than throttle");
+        }
+
+        let job_sets_repo = overrides.repo.is_some();
+        let settings = overrides.or(defaults);
+        let targets = match names {
+            None => vec![self.resolve(prefix, None, settings, defaults, repositories)],
+            Some(names) => {
+                let key = format!("{}.repositories", prefix);
+                if names.is_empty() {
+                    self.problem(&key, "at least one repository name is required");
+                }
+                if job_sets_repo {
+                    self.problem(&key, "cannot be combined with repo");
+                }
+                let mut targets = vec![];
+                for name in names {
+                    match repositories.iter().find(|(n, _)| *n == name) {
+                        Some((_, repository)) => {
+                            let settings = repository.clone().or(&settings);
+                            targets.push(self.resolve(prefix, Some(&name), settings, defaults, repositories))
+                        }
+                        None => self.problem(&key, format!("unknown repository '{}'", name)),
+                    }
+                }
+                targets
+            }
+        };
+        let targets = targets.into_iter().collect::<Option<Vec<_>>>();
+        Some(BackupJobConfig { name: name?, paths: paths?, watch, schedule, catch_up, throttle, max_wait, progress, progress_interval, timeout, retention, retry, options, hooks, targets: targets? })
+    }
+
+    fn resolve(
+        &mut self,
+        prefix: &str,
+        name: Option<&str>,
+        settings: ResticSettings,
+        defaults: &ResticSettings,
+        repositories: &[(String, ResticSettings)],
+    ) -> Option<BackupConfig> {
+        if settings.repo.is_none() {
+            self.problem(prefix, "no repo set for this job or at top level");
+        }
+        if settings.password.is_none() {
+            match name {
+                Some(name) => self.problem(prefix, format!("no password-command, password-file or password-env set for repository '{}', this job or at top level", name)),
+                None => self.problem(prefix, "no password-command, password-file or password-env set for this job or at top level"),
+            }
+        }
+        let init_if_missing = settings.init_if_missing.unwrap_or(false);
+        let copy_chunker_params = match settings.copy_chunker_params_from {
+            None => None,
+            Some(source) => {
+                if !init_if_missing {
+                    self.problem(prefix, "copy-chunker-params-from requires init-if-missing");
+                }
+                match repositories.iter().find(|(n, _)| *n == source) {
+                    // Lets a top-level setting name one of the repositories it applies to.
+                    Some(_) if name == Some(source.as_str()) => None,
+                    Some((n, repository)) => {
+                        let mut settings = repository.clone().or(defaults);
+                        settings.init_if_missing = Some(false);
+                        settings.copy_chunker_params_from = None;
+                        let source = self.resolve(&format!("repositories.{}", n), Some(n), settings, defaults, repositories)?;
+                        Some(Box::new(source))
+                    }
+                    None => {
+                        self.problem(prefix, format!("copy-chunker-params-from names unknown repository '{}'", source));
+                        None
+                    }
+                }
+            }
+        };
+        let repo = settings.repo?;
+        Some(BackupConfig {
+            name: name.map(str::to_owned).unwrap_or_else(|| repo.clone()),
+            repo,
+            exclude_file: settings.exclude_file,
+            password: settings.password?,
+            credentials: settings.credentials.unwrap_or_default(),
+            env: Environment { clear: settings.clear_env.unwrap_or(false), path: settings.env_path, vars: settings.env.unwrap_or_default() },
+            restic_path: settings.restic_path.unwrap_or_else(|| DEFAULT_RESTIC_PATH.to_owned()),
+            stale_lock_age: settings.stale_lock_age.unwrap_or(DEFAULT_STALE_LOCK_AGE),
+            max_parallel_backups: settings.max_parallel_backups.unwrap_or(DEFAULT_MAX_PARALLEL_BACKUPS),
+            check: settings.check,
+            init_if_missing,
+            copy_chunker_params,
+        })
+    }
+}
+
+/// Restic settings that may appear both at top level and in a job; unset
+/// keys fall back to the next level up.
+#[derive(Clone, Default)]
+struct ResticSettings {
+    repo: Option<String>,
+    exclude_file: Option<String>,
+    password: Option<PasswordSource>,
+    credentials: Option<Credentials>,
+    env_path: Option<String>,
+    clear_env: Option<bool>,
+    /// `env` merged with the dedicated variable keys.
+    env: Option<BTreeMap<String, String>>,
+    restic_path: Option<String>,
+    stale_lock_age: Option<u64>,
+    max_parallel_backups: Option<usize>,
+    init_if_missing: Option<bool>,
+    /// Name of an entry in `repositories`.
+    copy_chunker_params_from: Option<String>,
+    /// Only read at top level and in `repositories` entries.
+    check: Option<CheckConfig>,
+}
+
+impl ResticSettings {
+    fn read(f: &mut Fields) -> ResticSettings {
+        ResticSettings {
+            repo: f.optional("repo"),
+            exclude_file: f.optional("exclude-file"),
+            password: f.password(),
+            credentials: f.optional("credentials"),
+            env_path: f.optional("env-path"),
+            clear_env: f.optional("clear-env"),
+            env: f.env(),
+            restic_path: f.optional("restic-path"),
+            stale_lock_age: f.at_least("stale-lock-age", MIN_STALE_LOCK_AGE),
+            max_parallel_backups: f.positive("max-parallel-backups"),
+            init_if_missing: f.optional("init-if-missing"),
+            copy_chunker_params_from: f.optional("copy-chunker-params-from"),
+            check: None,
+        }
+    }
+
+    fn or(self, fallback: &ResticSettings) -> ResticSettings {
+        let fallback = fallback.clone();
+        ResticSettings {
+            repo: self.repo.or(fallback.repo),
+            exclude_file: self.exclude_file.or(fallback.exclude_file),
+            password: self.password.or(fallback.password),
+            credentials: match (self.credentials, fallback.credentials) {
+                (Some(own), Some(fallback)) => Some(own.or(fallback)),
+                (own, fallback) => own.or(fallback),
+            },
+            env_path: self.env_path.or(fallback.env_path),
+            clear_env: self.clear_env.or(fallback.clear_env),
+            env: match (self.env, fallback.env) {
+                (Some(own), Some(mut vars)) => {
+                    vars.extend(own);
+                    Some(vars)
+                }
+                (own, fallback) => own.or(fallback),
+            },
+            restic_path: self.restic_path.or(fallback.restic_path),
+            stale_lock_age: self.stale_lock_age.or(fallback.stale_lock_age),
+            max_parallel_backups: self.max_parallel_backups.or(fallback.max_parallel_backups),
+            init_if_missing: self.init_if_missing.or(fallback.init_if_missing),
+            copy_chunker_params_from: self.copy_chunker_params_from.or(fallback.copy_chunker_params_from),
+            check: self.check.or(fallback.check),
+        }
+    }
+}
+
+/// Pulls typed values out of one YAML mapping, recording a problem for
+/// every missing, mistyped or unknown key.
+struct Fields<'a> {
+    loader: &'a mut Loader,
+    map: Mapping,
+    prefix: String,
+}
+
+impl<'a> Fields<'a> {
+    fn new(loader: &'a mut Loader, map: Mapping, prefix: &str) -> Fields<'a> {
+        Fields { loader, map, prefix: prefix.to_owned() }
+    }
+
+    fn key(&self, key: &str) -> String {
+        if self.prefix.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", self.prefix, key)
+        }
+    }
+
+    fn take<T: DeserializeOwned>(&mut self, key: &str) -> Option<Result<T, String>> {
+        let value = self.map.remove(key)?;
+        Some(serde_yaml::from_value(value).map_err(|e| e.to_string()))
+    }
+
+    fn optional<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        match self.take(key)? {
+            Ok(v) => Some(v),
+            Err(e) => {
+                let key = self.key(key);
+                self.loader.problem(&key, e);
+                None
+            }
+        }
+    }
+
+    fn required<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        if !self.map.contains_key(key) {
+            let line = self.loader.lines.get(&self.prefix).copied();
+            self.loader.problems.push(Problem { key: self.key(key), line, message: "missing required key".to_owned() });
+            return None;
+        }
+        self.optional(key)
+    }
+
+    /// An optional count that must be at least 1.
+    fn positive(&mut self, key: &str) -> Option<usize> {
+        let value = self.optional::<usize>(key)?;
+        if value == 0 {
+            let key = self.key(key);
+            self.loader.problem(&key, "must be at least 1");
+            return None;
+        }
+        Some(value)
+    }
+
+    /// An optional number of seconds that must be at least `min`.
+    fn at_least(&mut self, key: &str, min: u64) -> Option<u64> {
+        let value = self.optional::<u64>(key)?;
+        if value < min {
+            let key = self.key(key);
+            self.loader.problem(&key, format!("must be at least {}", min));
+            return None;
+        }
+        Some(value)
+    }
+
+    /// The password source, of which at most one may be given.
+    fn password(&mut self) -> Option<PasswordSource> {
+        let mut sources = vec![];
+        if let Some(command) = self.optional("password-command") {
+            sources.push(PasswordSource::Command(command));
+        }
+        if let Some(file) = self.optional::<String>("password-file") {
+            if let Err(e) = secrets::check_permissions(&file) {
+                let key = self.key("password-file");
+                self.loader.problem(&key, e);
+            }
+            sources.push(PasswordSource::File(file));
+        }
+        if let Some(var) = self.optional::<String>("password-env") {
+            if std::env::var_os(&var).is_none() {
+                let key = self.key("password-env");
+                self.loader.problem(&key, format!("environment variable {} is not set", var));
+            }
+            sources.push(PasswordSource::Env(var));
+        }
+        if sources.len() > 1 {
+            let prefix = self.prefix.clone();
+            self.loader.problem(&prefix, "only one of password-command, password-file and password-env may be set");
+        }
+        sources.pop()
+    }
+
+    /// The `env` map together with the keys for well-known restic variables.
+    fn env(&mut self) -> Option<BTreeMap<String, String>> {
+        let mut vars = BTreeMap::new();
+        for (name, value) in self.optional::<BTreeMap<String, Value>>("env").unwrap_or_default() {
+            let value = match value {
+                Value::String(s) => s,
+                Value::Number(n) => n.to_string(),
+                Value::Bool(b) => b.to_string(),
+                _ => {
+                    let key = self.key(&format!("env.{}", name));
+                    self.loader.problem(&key, "expected a string or number");
+                    continue;
+                }
+            };
+            vars.insert(name, value);
+        }
+        let dedicated = [("cache-dir", "RESTIC_CACHE_DIR"), ("tmp-dir", "TMPDIR"), ("gomaxprocs", "GOMAXPROCS")];
+        for (key, var) in dedicated {
+            let value = match self.map.get(key) {
+                Some(Value::Number(n)) => Some(n.to_string()),
+                Some(_) => self.optional::<String>(key),
+                None => None,
+            };
+            self.map.remove(key);
+            if let Some(value) = value {
+                if vars.contains_key(var) {
+                    let key = self.key(key);
+                    self.loader.problem(&key, format!("conflicts with {} in env", var));
+                }
+                vars.insert(var.to_owned(), value);
+            }
+        }
+        for (name, value) in &vars {
+            if let Some(message) = env::problem(name, value) {
+                let key = self.key(&format!("env.{}", name));
+                self.loader.problem(&key, message);
+            }
+        }
+        (!vars.is_empty()).then_some(vars)
+    }
+
+    /// A required key holding one path or a list of paths.
+    fn paths(&mut self, key: &str) -> Option<Vec<String>> {
+        let paths = match self.required::<Value>(key)? {
+            Value::String(path) => vec![path],
+            value => match serde_yaml::from_value::<Vec<String>>(value) {
+                Ok(paths) => paths,
+                Err(_) => {
+                    let key = self.key(key);
+                    self.loader.problem(&key, "expected a path or a list of paths");
+                    return None;
+                }
+            },
+        };
+        let key = self.key(key);
+        if paths.is_empty() {
+            self.loader.problem(&key, "at least one path is required");
+            return None;
+        }
+        for (i, path) in paths.iter().enumerate() {
+            if paths[..i].contains(path) {
+                self.loader.problem(&key, format!("duplicate path '{}'", path));
+            }
+        }
+        Some(paths)
+    }
+
+    fn finish(self) {
+        let unknown: Vec<String> = self
+            .map
+            .keys()
+            .map(|key| match key {
+                Value::String(s) => self.key(s),
+                other => self.key(&format!("{:?}", other)),
+            })
+            .collect();
+        for key in unknown {
+            self.loader.problem(&key, "unknown key");
+        }
+    }
+}
+
+/// Maps dotted key paths such as `dirs[1].throttle` to the line they were
+/// declared on, so problems can point at the offending line.
+struct LineIndex {
+    stack: Vec<Frame>,
+    lines: HashMap<String, usize>,
+}
+
+struct Frame {
+    path: String,
+    state: FrameState,
+}
+
+enum FrameState {
+    Mapping(Option<String>),
+    Sequence(usize),
+}
+
+impl LineIndex {
+    fn build(text: &str) -> HashMap<String, usize> {
+        let mut index = LineIndex { stack: vec![], lines: HashMap::new() };
+        // Syntax errors are reported by serde_yaml, so a partial index is fine.
+        let _ = Parser::new(text.chars()).load(&mut index, false);
+        index.lines
+    }
+
+    /// Path of the node that is about to start in the current container.
+    fn child_path(&self) -> Option<String> {
+        let frame = self.stack.last()?;
+        Some(match &frame.state {
+            FrameState::Mapping(Some(key)) if frame.path.is_empty() => key.clone(),
+            FrameState::Mapping(Some(key)) => format!("{}.{}", frame.path, key),
+            FrameState::Mapping(None) => return None,
+            FrameState::Sequence(i) => format!("{}[{}]", frame.path, i),
+        })
+    }
+
+    /// Marks the current child node as complete.
+    fn consume(&mut self) {
+        if let Some(frame) = self.stack.last_mut() {
+            match &mut frame.state {
+                FrameState::Mapping(key) => *key = None,
+                FrameState::Sequence(i) => *i += 1,
+            }
+        }
+    }
+
+    fn start(&mut self, state: FrameState, mark: Marker) {
+        let path = self.child_path().unwrap_or_default();
+        self.lines.entry(path.clone()).or_insert(mark.line());
+        self.stack.push(Frame { path, state });
+    }
+}
+
+impl MarkedEventReceiver for LineIndex {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        match ev {
+            Event::MappingStart(_) => self.start(FrameState::Mapping(None), mark),
+            Event::SequenceStart(_) => self.start(FrameState::Sequence(0), mark),
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+                self.consume();
+            }
+            Event::Scalar(value, ..) => {
+                if let Some(Frame { path, state: FrameState::Mapping(key @ None) }) = self.stack.last_mut() {
+                    let full = if path.is_empty() { value.clone() } else { format!("{}.{}", path, value) };
+                    self.lines.insert(full, mark.line());
+                    *key = Some(value);
+                } else {
+                    if let Some(path) = self.child_path() {
+                        self.lines.entry(path).or_insert(mark.line());
+                    }
+                    self.consume();
+                }
+            }
+            Event::Alias(_) => self.consume(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (line, key, message) of each problem in `text`.
+    fn problems(text: &str) -> Vec<(Option<usize>, String, String)> {
+        match Config::parse("test.yml", text) {
+            Ok(_) => vec![],
+            Err(e) => e.problems.into_iter().map(|p| (p.line, p.key, p.message)).collect(),
+        }
+    }
+
+    #[test]
+    fn minimal_config_uses_defaults() {
+        let config = Config::parse("test.yml", "password-command: echo hi\nrepo: /srv/repo\ndirs:\n  - name: home\n    path: /home\n").unwrap();
+        assert_eq!(config.logfile, DEFAULT_LOGFILE);
+        assert_eq!(config.shutdown_grace, DEFAULT_SHUTDOWN_GRACE);
+        let job = &config.dirs[0];
+        assert_eq!(job.paths, ["/home"]);
+        assert!(job.watch);
+        assert_eq!(job.throttle, DEFAULT_THROTTLE);
+        assert_eq!(job.options.tags, ["home"]);
+        assert_eq!(job.targets.len(), 1);
+        assert_eq!(job.targets[0].repo, "/srv/repo");
+        assert_eq!(job.targets[0].max_parallel_backups, DEFAULT_MAX_PARALLEL_BACKUPS);
+    }
+
+    #[test]
+    fn every_problem_is_reported_with_its_line() {
+        let text = concat!(
+            "password-command: echo hi\n",
+            "repo: /srv/repo\n",
+            "bogus: 1\n",
+            "dirs:\n",
+            "  - name: home\n",
+            "    path: /home\n",
+            "    throtle: 5\n",
+            "  - path: /etc\n",
+            "    throttle: soon\n",
+        );
+        let problems = problems(text);
+        let keys: Vec<(Option<usize>, &str)> = problems.iter().map(|(line, key, _)| (*line, key.as_str())).collect();
+        assert_eq!(
+            keys,
+            [(Some(3), "bogus"), (Some(7), "dirs[0].throtle"), (Some(8), "dirs[1].name"), (Some(9), "dirs[1].throttle")]
+        );
+        assert_eq!(problems[0].2, "unknown key");
+        assert_eq!(problems[1].2, "unknown key");
+        assert_eq!(problems[2].2, "missing required key");
+        assert!(problems[3].2.contains("invalid type"), "{}", problems[3].2);
+    }
+
+    #[test]
+    fn syntax_errors_carry_the_line() {
+        let problems = problems("dirs: [\n");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, Some(2));
+    }
+
+    #[test]
+    fn top_level_must_be_a_mapping() {
+        let problems = problems("- a\n- b\n");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].2, "top level must be a mapping");
+    }
+
+    #[test]
+    fn duplicate_job_names_are_rejected() {
+        let text = "password-command: echo hi\nrepo: /srv/repo\ndirs:\n  - name: a\n    path: /home\n  - name: a\n    path: /etc\n";
+        assert_eq!(problems(text), [(Some(6), "dirs[1].name".to_owned(), "duplicate job name 'a'".to_owned())]);
+    }
+
+    #[test]
+    fn jobs_need_a_repository_and_a_trigger() {
+        let text = "password-command: echo hi\ndirs:\n  - name: a\n    path: /home\n    watch: false\n";
+        let messages: Vec<String> = problems(text).into_iter().map(|(_, _, message)| message).collect();
+        assert_eq!(messages, ["job neither watches its paths nor has a schedule", "no repo set for this job or at top level"]);
+    }
+
+    #[test]
+    fn conflicting_parallel_backup_limits_are_rejected() {
+        let text = concat!(
+            "password-command: echo hi\n",
+            "repo: /srv/repo\n",
+            "dirs:\n",
+            "  - {name: a, path: /home}\n",
+            "  - {name: b, path: /etc, max-parallel-backups: 2}\n",
+        );
+        let problems = problems(text);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].1, "dirs[1]");
+    }
+
+    #[test]
+    fn stale_lock_age_has_a_floor() {
+        let text = "password-command: echo hi\nrepo: /srv/repo\nstale-lock-age: 0\ndirs:\n  - {name: a, path: /home, stale-lock-age: 1800}\n";
+        assert_eq!(problems(text), [(Some(3), "stale-lock-age".to_owned(), "must be at least 1800".to_owned())]);
+    }
+
+    #[test]
+    fn history_file_is_read_without_validating_the_rest() {
+        let path = std::env::temp_dir().join(format!("restic-automator-history-{}.yml", std::process::id()));
+        std::fs::write(&path, "password-env: RESTIC_AUTOMATOR_TEST_UNSET\nhistory-file: /var/log/runs.jsonl\n").unwrap();
+        let file = Config::load_history_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.unwrap().as_deref(), Some("/var/log/runs.jsonl"));
+    }
+}
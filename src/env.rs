@@ -0,0 +1,74 @@
+//! The environment restic runs in, so that backups behave the same whether
+//! the daemon was started by systemd, cron or a login shell.
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+/// `PATH` given to restic when the inherited environment is cleared and no
+/// `env-path` is configured.
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Variables with a dedicated config key, which `env` must not set.
+const RESERVED_VARS: [(&str, &str); 6] = [
+    ("PATH", "env-path"),
+    ("RESTIC_REPOSITORY", "repo"),
+    ("RESTIC_REPOSITORY_FILE", "repo"),
+    ("RESTIC_PASSWORD", "password-env"),
+    ("RESTIC_PASSWORD_FILE", "password-file"),
+    ("RESTIC_PASSWORD_COMMAND", "password-command"),
+];
+
+/// Smallest and largest pack size restic accepts, in MiB.
+pub const PACK_SIZE_RANGE: std::ops::RangeInclusive<u32> = 4..=128;
+
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    /// Start from an empty environment instead of the daemon's.
+    pub clear: bool,
+    /// Replaces `PATH`, from `env-path`.
+    pub path: Option<String>,
+    /// Variables set on top, from `env` and the dedicated keys such as `cache-dir`.
+    pub vars: BTreeMap<String, String>,
+}
+
+impl Environment {
+    pub fn apply(&self, cmd: &mut Command) {
+        if self.clear {
+            cmd.env_clear();
+            cmd.env("PATH", self.path.as_deref().unwrap_or(DEFAULT_PATH));
+        } else if let Some(path) = &self.path {
+            cmd.env("PATH", path);
+        }
+        cmd.envs(&self.vars);
+    }
+
+    /// The `PATH` commands are looked up in.
+    pub fn search_path(&self) -> Option<String> {
+        match (&self.path, self.clear) {
+            (Some(path), _) => Some(path.clone()),
+            (None, true) => Some(DEFAULT_PATH.to_owned()),
+            (None, false) => std::env::var("PATH").ok(),
+        }
+    }
+}
+
+/// Why `value` cannot be used for variable `name`, if it cannot.
+pub fn problem(name: &str, value: &str) -> Option<String> {
+    if name.is_empty() || name.contains(['=', '\0']) {
+        return Some(format!("'{}' is not a valid variable name", name));
+    }
+    if let Some((_, key)) = RESERVED_VARS.iter().find(|(var, _)| *var == name) {
+        return Some(format!("{} cannot be set here, use {} instead", name, key));
+    }
+    match name {
+        "GOMAXPROCS" if !value.parse::<u32>().is_ok_and(|n| n > 0) => Some(format!("GOMAXPROCS must be a positive number, got '{}'", value)),
+        "RESTIC_PACK_SIZE" if !value.parse::<u32>().is_ok_and(|n| PACK_SIZE_RANGE.contains(&n)) => Some(format!(
+            "RESTIC_PACK_SIZE must be between {} and {} MiB, got '{}'",
+            PACK_SIZE_RANGE.start(),
+            PACK_SIZE_RANGE.end(),
+            value
+        )),
+        "RESTIC_CACHE_DIR" | "TMPDIR" if !value.starts_with('/') => Some(format!("{} must be an absolute path, got '{}'", name, value)),
+        _ => None,
+    }
+}
@@ -0,0 +1,200 @@
+//! Append-only run history: one JSON line per backup to a repository,
+//! written to `history-file`, and the `history` subcommand that prints it.
+
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::job::Trigger;
+use crate::messages::{BackupReport, Summary};
+use crate::restic::{BackupError, EXIT_INCOMPLETE};
+use crate::status::{format_bytes, unix_now};
+
+/// One backup of a job to one repository. Times are Unix seconds.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RunRecord {
+    pub job: String,
+    pub repository: String,
+    pub trigger: String,
+    pub started: u64,
+    pub finished: u64,
+    /// restic's exit code; `None` when it was killed or never ran.
+    pub exit_code: Option<i32>,
+    pub success: bool,
+    pub summary: Option<Summary>,
+    pub snapshot_id: Option<String>,
+    /// Files restic could not read.
+    #[serde(default)]
+    pub file_errors: usize,
+    pub error: Option<String>,
+}
+
+impl RunRecord {
+    pub fn new(job: &str, repository: &str, trigger: Trigger, started: u64, result: &Result<BackupReport, BackupError>) -> RunRecord {
+        let (exit_code, summary, file_errors, error) = match result {
+            // restic exits with 3 whenever it reported unreadable files.
+            Ok(report) => (Some(if report.errors > 0 { EXIT_INCOMPLETE } else { 0 }), Some(report.summary.clone()), report.errors, None),
+            Err(e) => {
+                let code = match e {
+                    BackupError::Exit { code, .. } => *code,
+                    _ => None,
+                };
+                (code, None, 0, Some(e.to_string()))
+            }
+        };
+        RunRecord {
+            job: job.to_owned(),
+            repository: repository.to_owned(),
+            trigger: trigger.to_string(),
+            started,
+            finished: unix_now(),
+            exit_code,
+            success: result.is_ok(),
+            snapshot_id: summary.as_ref().and_then(|s| s.snapshot_id.clone()),
+            summary,
+            file_errors,
+            error,
+        }
+    }
+
+    /// One line for the `history` subcommand.
+    fn describe(&self) -> String {
+        let outcome = match (&self.summary, &self.error) {
+            (Some(s), _) => format!(
+                "snapshot {}, {} new, {} changed, {} added{}",
+                self.snapshot_id.as_deref().unwrap_or("none"),
+                s.files_new,
+                s.files_changed,
+                format_bytes(s.data_added),
+                if self.file_errors > 0 { format!(", {} unreadable", self.file_errors) } else { String::new() }
+            ),
+            (None, Some(e)) => e.clone(),
+            (None, None) => String::new(),
+        };
+        format!(
+            "{}  {}  {}  {}  {}  {}s  {}",
+            local_time(self.started),
+            self.job,
+            self.repository,
+            self.trigger,
+            if self.success { "success" } else { "failure" },
+            self.finished.saturating_sub(self.started),
+            outcome
+        )
+    }
+}
+
+pub struct History {
+    file: Option<String>,
+    lock: Mutex<()>,
+}
+
+impl History {
+    pub fn new(file: Option<String>) -> Arc<History> {
+        Arc::new(History { file, lock: Mutex::new(()) })
+    }
+
+    /// Appends a record as a single line, so concurrent writers never interleave.
+    pub fn append(&self, record: &RunRecord) {
+        let Some(file) = &self.file else { return };
+        let _lock = self.lock.lock().unwrap();
+        let result = serde_json::to_string(record).map_err(std::io::Error::from).and_then(|mut line| {
+            line.push('\n');
+            OpenOptions::new().create(true).append(true).open(file)?.write_all(line.as_bytes())
+        });
+        if let Err(e) = result {
+            warn!("Unable to append to history file {}: {}", file, e);
+        }
+    }
+}
+
+/// Which records the `history` subcommand prints.
+#[derive(Default)]
+pub struct Filter {
+    pub job: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Print the raw JSON lines instead of a summary.
+    pub json: bool,
+}
+
+impl Filter {
+    /// Parses `--job NAME`, `--since TIME`, `--until TIME` and `--json`,
+    /// returning the filter and the remaining arguments.
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<(Filter, Vec<String>), String> {
+        let mut filter = Filter::default();
+        let mut rest = vec![];
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+            match arg.as_str() {
+                "--job" => filter.job = Some(value("--job")?),
+                "--since" => filter.since = Some(parse_time(&value("--since")?)?),
+                "--until" => filter.until = Some(parse_time(&value("--until")?)?),
+                "--json" => filter.json = true,
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ => rest.push(arg),
+            }
+        }
+        Ok((filter, rest))
+    }
+
+    fn matches(&self, record: &RunRecord) -> bool {
+        self.job.as_ref().is_none_or(|job| *job == record.job)
+            && self.since.is_none_or(|since| record.started >= since)
+            && self.until.is_none_or(|until| record.started < until)
+    }
+}
+
+/// Parses an RFC 3339 time, a local date such as `2024-05-01`, or a
+/// duration such as `2d` meaning that long ago.
+fn parse_time(value: &str) -> Result<u64, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp().max(0) as u64);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).and_then(|t| Local.from_local_datetime(&t).earliest());
+        return midnight.map(|t| t.timestamp().max(0) as u64).ok_or(format!("{} has no local midnight", value));
+    }
+    match humantime::parse_duration(value) {
+        Ok(ago) => Ok(unix_now().saturating_sub(ago.as_secs())),
+        Err(_) => Err(format!("invalid time '{}', expected e.g. 2024-05-01, 2024-05-01T12:00:00Z or 2d", value)),
+    }
+}
+
+fn local_time(unix: u64) -> String {
+    match Local.timestamp_opt(unix as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => unix.to_string(),
+    }
+}
+
+/// Prints the matching records of `file`. Lines that do not parse are
+/// reported on stderr and skipped.
+pub fn print(file: &str, filter: &Filter) -> Result<(), String> {
+    let reader = BufReader::new(std::fs::File::open(file).map_err(|e| format!("{}: {}", file, e))?);
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", file, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = match serde_json::from_str::<RunRecord>(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("{}:{}: skipping unreadable record: {}", file, number + 1, e);
+                continue;
+            }
+        };
+        if !filter.matches(&record) {
+            continue;
+        }
+        if filter.json {
+            println!("{}", line);
+        } else {
+            println!("{}", record.describe());
+        }
+    }
+    Ok(())
+}
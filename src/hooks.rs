@@ -0,0 +1,240 @@
+//! Commands run around a job's backup, such as dumping a database before
+//! it or sending a notification after it.
+
+use std::fmt;
+use std::process::Command;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::process::Child;
+
+use crate::config::BackupJobConfig;
+use crate::job::Trigger;
+use crate::messages::BackupReport;
+use crate::restic::{self, BackupError};
+
+const DEFAULT_HOOK_TIMEOUT: u64 = 300;
+
+/// How long a timed-out hook gets to exit after SIGTERM before it is killed.
+const HOOK_KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Prefix of the environment variables describing the run to a hook.
+const ENV_PREFIX: &str = "RESTIC_AUTOMATOR_";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookKind {
+    Before,
+    After,
+    OnSuccess,
+    OnFailure,
+}
+
+impl fmt::Display for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HookKind::Before => "before",
+            HookKind::After => "after",
+            HookKind::OnSuccess => "on-success",
+            HookKind::OnFailure => "on-failure",
+        })
+    }
+}
+
+/// A `hooks:` block, at top level or in a job. Each hook is a shell command
+/// or a list of them, run in order.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Hooks {
+    /// Failing `before` commands abort the backup.
+    #[serde(deserialize_with = "commands")]
+    pub before: Vec<String>,
+    /// Always run once the backup has finished or was aborted.
+    #[serde(deserialize_with = "commands")]
+    pub after: Vec<String>,
+    #[serde(deserialize_with = "commands")]
+    pub on_success: Vec<String>,
+    #[serde(deserialize_with = "commands")]
+    pub on_failure: Vec<String>,
+    /// Seconds each command may run before it is terminated.
+    pub timeout: Option<u64>,
+}
+
+fn commands<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    match OneOrMany::deserialize(d) {
+        Ok(OneOrMany::One(command)) => Ok(vec![command]),
+        Ok(OneOrMany::Many(commands)) => Ok(commands),
+        Err(_) => Err(serde::de::Error::custom("expected a command or a list of commands")),
+    }
+}
+
+impl Hooks {
+    fn commands(&self, kind: HookKind) -> &[String] {
+        match kind {
+            HookKind::Before => &self.before,
+            HookKind::After => &self.after,
+            HookKind::OnSuccess => &self.on_success,
+            HookKind::OnFailure => &self.on_failure,
+        }
+    }
+}
+
+/// What a hook is told about the run it belongs to.
+pub struct RunInfo<'a> {
+    pub trigger: Trigger,
+    /// Successful backups by repository name; empty for `before` hooks.
+    pub reports: &'a [(String, BackupReport)],
+    /// Why the run failed, for `after` and `on-failure` hooks.
+    pub errors: &'a [String],
+    /// Whether shutdown cancelled a backup before restic started.
+    pub cancelled: bool,
+}
+
+impl RunInfo<'_> {
+    /// Environment variables for a hook. Statistics come from the first
+    /// repository that was backed up successfully.
+    fn env(&self, job: &BackupJobConfig, kind: HookKind) -> Vec<(String, String)> {
+        let mut vars = vec![
+            ("JOB", job.name.clone()),
+            ("PATHS", job.paths.join("\n")),
+            ("TRIGGER", self.trigger.to_string()),
+            ("HOOK", kind.to_string()),
+        ];
+        if kind != HookKind::Before {
+            let status = match (self.errors.is_empty(), self.cancelled) {
+                (false, _) => "failure",
+                (true, true) => "cancelled",
+                (true, false) => "success",
+            };
+            vars.push(("STATUS", status.to_owned()));
+        }
+        if !self.errors.is_empty() {
+            vars.push(("ERROR", self.errors.join("\n")));
+        }
+        let ids: Vec<String> = self
+            .reports
+            .iter()
+            .filter_map(|(repo, report)| report.summary.snapshot_id.as_ref().map(|id| format!("{}={}", repo, id)))
+            .collect();
+        if !ids.is_empty() {
+            vars.push(("SNAPSHOT_IDS", ids.join(" ")));
+        }
+        if let Some((repo, report)) = self.reports.first() {
+            let s = &report.summary;
+            vars.push(("REPOSITORY", repo.clone()));
+            if let Some(id) = &s.snapshot_id {
+                vars.push(("SNAPSHOT_ID", id.clone()));
+            }
+            vars.push(("FILES_NEW", s.files_new.to_string()));
+            vars.push(("FILES_CHANGED", s.files_changed.to_string()));
+            vars.push(("FILES_UNMODIFIED", s.files_unmodified.to_string()));
+            vars.push(("DIRS_NEW", s.dirs_new.to_string()));
+            vars.push(("DIRS_CHANGED", s.dirs_changed.to_string()));
+            vars.push(("DATA_ADDED", s.data_added.to_string()));
+            vars.push(("TOTAL_FILES_PROCESSED", s.total_files_processed.to_string()));
+            vars.push(("TOTAL_BYTES_PROCESSED", s.total_bytes_processed.to_string()));
+            vars.push(("TOTAL_DURATION", s.total_duration.to_string()));
+            vars.push(("ERROR_COUNT", report.errors.to_string()));
+        }
+        vars.into_iter().map(|(name, value)| (format!("{}{}", ENV_PREFIX, name), value)).collect()
+    }
+}
+
+/// Runs the job's hooks of one kind: top-level hooks first for `before`,
+/// job hooks first for the others. A failing `before` command stops the
+/// remaining ones and is returned as the error; failures of the other kinds
+/// are logged and the remaining commands still run.
+pub async fn run(job: &BackupJobConfig, kind: HookKind, info: &RunInfo<'_>) -> Result<(), String> {
+    let mut sets: Vec<&Hooks> = job.hooks.iter().collect();
+    if kind != HookKind::Before {
+        sets.reverse();
+    }
+    let env = info.env(job, kind);
+    for hooks in sets {
+        let timeout = Duration::from_secs(hooks.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT));
+        for command in hooks.commands(kind) {
+            info!("{} running {} hook: {}", job.name, kind, command);
+            match run_command(command, &env, timeout).await {
+                Ok(output) => log_output(job, kind, &output),
+                Err((e, output)) => {
+                    log_output(job, kind, &output);
+                    let message = format!("{} hook '{}' failed: {}", kind, command, e);
+                    error!("{} {}", job.name, message);
+                    if kind == HookKind::Before {
+                        return Err(message);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn log_output(job: &BackupJobConfig, kind: HookKind, output: &str) {
+    for line in output.lines().filter(|l| !l.trim().is_empty()) {
+        info!("{} {} hook: {}", job.name, kind, line);
+    }
+}
+
+/// Runs one hook command through `sh -c`, returning its combined output.
+/// On failure the output is returned along with the reason. The timeout
+/// covers everything the command starts, not just the shell.
+async fn run_command(command: &str, env: &[(String, String)], timeout: Duration) -> Result<String, (String, String)> {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command).envs(env.iter().map(|(k, v)| (k, v)));
+    let mut child = match restic::spawn(cmd) {
+        Ok(child) => child,
+        Err(BackupError::Spawn(e)) | Err(BackupError::Io(e)) => return Err((format!("unable to start: {}", e), String::new())),
+        Err(e) => return Err((e.to_string(), String::new())),
+    };
+    // The shell leads its own process group, so the group id is its pid.
+    let group = child.id().map(|pid| pid as libc::pid_t);
+    let mut stdout = tokio::spawn(restic::read_all(child.stdout.take().unwrap()));
+    let mut stderr = tokio::spawn(restic::read_all(child.stderr.take().unwrap()));
+    // Output is only complete once every process holding the pipes exited.
+    let output = async {
+        let out = (&mut stdout).await.unwrap_or_default();
+        out + &(&mut stderr).await.unwrap_or_default()
+    };
+    let finished = tokio::time::timeout(timeout, async { tokio::join!(child.wait(), output) }).await;
+    let (status, output) = match finished {
+        Ok((Ok(status), output)) => (status, output),
+        Ok((Err(e), output)) => return Err((format!("unable to wait for it: {}", e), output)),
+        Err(_) => {
+            terminate_group(group, &mut child).await;
+            let output = async { stdout.await.unwrap_or_default() + &stderr.await.unwrap_or_default() };
+            let output = tokio::time::timeout(HOOK_KILL_GRACE, output).await.unwrap_or_default();
+            return Err((format!("did not finish within {}s and was terminated", timeout.as_secs()), output));
+        }
+    };
+    if status.success() {
+        return Ok(output);
+    }
+    match status.code() {
+        Some(code) => Err((format!("exited with code {}", code), output)),
+        None => Err(("was terminated by a signal".to_owned(), output)),
+    }
+}
+
+/// Sends SIGTERM to the hook's process group, then SIGKILL to whatever is
+/// left of it once the shell has exited or the grace period is over.
+async fn terminate_group(group: Option<libc::pid_t>, child: &mut Child) {
+    let Some(group) = group else { return };
+    signal_group(group, libc::SIGTERM);
+    let _ = tokio::time::timeout(HOOK_KILL_GRACE, child.wait()).await;
+    signal_group(group, libc::SIGKILL);
+    let _ = child.wait().await;
+}
+
+fn signal_group(group: libc::pid_t, signal: libc::c_int) {
+    // SAFETY: kill(2) has no memory-safety preconditions; a negative pid
+    // addresses the process group.
+    unsafe {
+        libc::kill(-group, signal);
+    }
+}
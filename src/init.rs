@@ -0,0 +1,57 @@
+//! Creation of repositories configured with `init-if-missing`.
+
+use crate::config::BackupConfig;
+use crate::restic::{self, BackupError};
+
+/// restic's exit code when the repository does not exist.
+const EXIT_NO_REPOSITORY: i32 = 10;
+
+/// Whether a failed restic run means the repository is not there. restic
+/// before 0.17 exits with 1 in this case, so its message is checked too.
+pub fn is_missing(error: &BackupError) -> bool {
+    match error {
+        BackupError::Exit { code: Some(EXIT_NO_REPOSITORY), .. } => true,
+        BackupError::Exit { code: Some(1), stderr } => stderr.contains("Is there a repository at the following location?"),
+        _ => false,
+    }
+}
+
+/// Probes the repository with `cat config`.
+pub async fn exists(config: &BackupConfig) -> Result<bool, BackupError> {
+    let mut cmd = restic::command(config);
+    cmd.arg("--no-lock").arg("cat").arg("config");
+    match restic::output(cmd).await {
+        Ok(_) => Ok(true),
+        Err(e) if is_missing(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs `restic init`, copying the chunker parameters from the configured
+/// source repository so that both deduplicate the same way. The source is
+/// opened with the new repository's backend credentials.
+pub async fn init(config: &BackupConfig) -> Result<(), BackupError> {
+    let mut cmd = restic::command(config);
+    cmd.arg("init");
+    if let Some(source) = &config.copy_chunker_params {
+        info!("{}: initializing repository with chunker parameters from {}.", config.name, source.name);
+        cmd.arg("--copy-chunker-params").arg("--from-repo").arg(&source.repo);
+        source.password.apply_from(&mut cmd);
+    } else {
+        info!("{}: initializing repository.", config.name);
+    }
+    restic::output(cmd).await?;
+    info!("{}: repository initialized at {}.", config.name, config.repo);
+    Ok(())
+}
+
+/// Initializes the repository unless it already exists. Returns whether it
+/// was created.
+pub async fn init_if_missing(config: &BackupConfig) -> Result<bool, BackupError> {
+    if exists(config).await? {
+        return Ok(false);
+    }
+    warn!("{}: repository {} does not exist.", config.name, config.repo);
+    init(config).await?;
+    Ok(true)
+}
@@ -0,0 +1,339 @@
+//! Running a job's backup against every repository it targets.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::config::{BackupConfig, BackupJobConfig};
+use crate::history::{History, RunRecord};
+use crate::hooks::{self, HookKind, RunInfo};
+use crate::init;
+use crate::locks::{self, RepoGuards};
+use crate::messages::BackupReport;
+use crate::restic::{self, BackupError};
+use crate::retention::{self, Retention};
+use crate::retry::FailureClass;
+use crate::scheduler::Scheduler;
+use crate::shutdown::{Phase, Shutdown};
+use crate::snapshots;
+use crate::state::StateStore;
+use crate::status::{format_duration, unix_now, StatusBoard};
+
+/// Daemon-wide services shared by every job.
+#[derive(Clone)]
+pub struct Context {
+    pub status: Arc<StatusBoard>,
+    pub state: Arc<StateStore>,
+    pub history: Arc<History>,
+    pub shutdown: Shutdown,
+    pub repos: Arc<RepoGuards>,
+    pub scheduler: Arc<Scheduler>,
+}
+
+/// What started a job run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// Filesystem changes under the job's path.
+    Changes,
+    /// The job's `schedule`.
+    Schedule,
+    /// The startup check for changes made while the daemon was stopped.
+    CatchUp,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Trigger::Changes => "changes",
+            Trigger::Schedule => "schedule",
+            Trigger::CatchUp => "catch-up",
+        })
+    }
+}
+
+/// A configured job and the guard that keeps its runs from overlapping,
+/// whichever trigger starts them.
+pub struct Job {
+    pub config: BackupJobConfig,
+    running: Mutex<()>,
+    /// Whether a backup is waiting for the running one to finish.
+    queued: AtomicBool,
+}
+
+impl Job {
+    pub fn new(config: BackupJobConfig) -> Arc<Job> {
+        Arc::new(Job { config, running: Mutex::new(()), queued: AtomicBool::new(false) })
+    }
+
+    /// Runs a backup, waiting for any run already in progress to finish first.
+    /// At most one run waits: if one is already queued, it will cover this
+    /// trigger's changes too and this call returns right away.
+    pub async fn run(&self, trigger: Trigger, ctx: &Context) -> Result<(), ()> {
+        let _guard = match self.running.try_lock() {
+            Ok(guard) => guard,
+            Err(_) if self.queued.swap(true, Ordering::SeqCst) => {
+                info!("{} already has a backup queued, ignoring {} trigger.", self.config.name, trigger);
+                return Ok(());
+            }
+            Err(_) => {
+                info!("{} is already running, {} run will start once it finishes.", self.config.name, trigger);
+                let guard = self.running.lock().await;
+                self.queued.store(false, Ordering::SeqCst);
+                guard
+            }
+        };
+        info!("{} Backup on {} triggered by {}.", self.config.name, self.config.paths_label(), trigger);
+        let result = self.backup_with_hooks(trigger, ctx).await;
+        if let Some(retention) = &self.config.retention {
+            if result.is_ok() && retention.schedule.is_none() && ctx.shutdown.phase() == Phase::Running {
+                forget(&self.config, retention, ctx).await;
+            }
+        }
+        result
+    }
+
+    /// Runs the backup between the job's hooks. A failing `before` hook
+    /// skips the backup but still runs the `on-failure` and `after` hooks.
+    /// Once shutdown has started no hooks run, and backups cancelled by it
+    /// do not count as failures.
+    async fn backup_with_hooks(&self, trigger: Trigger, ctx: &Context) -> Result<(), ()> {
+        let job = &self.config;
+        if ctx.shutdown.phase() != Phase::Running {
+            info!("{} Backup on {} skipped due to shutdown.", job.name, job.paths_label());
+            return Err(());
+        }
+        let mut info = RunInfo { trigger, reports: &[], errors: &[], cancelled: false };
+        let mut cancelled = 0;
+        let (reports, errors) = match hooks::run(job, HookKind::Before, &info).await {
+            Ok(()) => {
+                let mut reports = vec![];
+                let mut errors = vec![];
+                for (target, result) in backup(job, trigger, ctx).await {
+                    match result {
+                        Ok(report) => reports.push((target, report)),
+                        Err(BackupError::Cancelled) => cancelled += 1,
+                        Err(e) => errors.push(format!("backup to {} failed: {}", target, e)),
+                    }
+                }
+                (reports, errors)
+            }
+            Err(e) => {
+                error!("{} Backup on {} skipped because a before hook failed.", job.name, job.paths_label());
+                (vec![], vec![e])
+            }
+        };
+        info.reports = &reports;
+        info.errors = &errors;
+        info.cancelled = cancelled > 0;
+        if !errors.is_empty() {
+            let _ = hooks::run(job, HookKind::OnFailure, &info).await;
+        } else if cancelled == 0 {
+            let _ = hooks::run(job, HookKind::OnSuccess, &info).await;
+        }
+        // Undoes whatever the before hooks set up, even for cancelled backups.
+        let _ = hooks::run(job, HookKind::After, &info).await;
+        if errors.is_empty() && cancelled == 0 {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// Applies the job's retention policy, waiting for any running backup.
+    pub async fn apply_retention(&self, ctx: &Context) {
+        if let Some(retention) = &self.config.retention {
+            let _guard = self.running.lock().await;
+            forget(&self.config, retention, ctx).await;
+        }
+    }
+}
+
+async fn forget(job: &BackupJobConfig, retention: &Retention, ctx: &Context) {
+    for target in &job.targets {
+        let _exclusive = ctx.repos.exclusive(&target.repo).await;
+        let _permit = ctx.scheduler.process_slot(&format!("{} Retention on {}", job.name, target.name)).await;
+        // Shutdown may have started while waiting for the repository.
+        if ctx.shutdown.phase() != Phase::Running {
+            info!("{} Retention on {} skipped due to shutdown.", job.name, target.name);
+            return;
+        }
+        let result = retention::apply(job, target, retention, &ctx.shutdown).await;
+        if let Err(e) = &result {
+            error!("{} Retention on {} failed: {}", job.name, target.name, e);
+        }
+        ctx.state.record_forget(&job.name, &target.name, &result);
+    }
+}
+
+/// Backs the job up to all of its repositories concurrently and returns the
+/// result for each repository name. A failing repository does not stop the
+/// others. Each result is saved to the job state and the run history.
+async fn backup(job: &BackupJobConfig, trigger: Trigger, ctx: &Context) -> Vec<(String, Result<BackupReport, BackupError>)> {
+    let results = futures::future::join_all(job.targets.iter().map(|target| async move {
+        let started = unix_now();
+        let result = backup_with_retry(job, target, ctx).await;
+        if !matches!(result, Err(BackupError::Cancelled)) {
+            ctx.state.record_backup(&job.name, &target.name, started, &result);
+            ctx.status.refresh();
+            ctx.history.append(&RunRecord::new(&job.name, &target.name, trigger, started, &result));
+        }
+        match &result {
+            Ok(report) => {
+                let s = &report.summary;
+                info!(
+                    "{} Backup to {} Complete. - {} new, {} changed, {} unmodified, {} bytes added, finished in {} seconds. Snapshot {}.",
+                    job.name,
+                    target.name,
+                    s.files_new,
+                    s.files_changed,
+                    s.files_unmodified,
+                    s.data_added,
+                    s.total_duration,
+                    s.snapshot_id.as_deref().unwrap_or("none")
+                );
+                if report.errors > 0 {
+                    warn!("{} Backup to {} skipped {} unreadable item(s).", job.name, target.name, report.errors);
+                }
+            }
+            Err(BackupError::Cancelled) => info!("{} Backup to {} {}.", job.name, target.name, BackupError::Cancelled),
+            Err(e) => error!("{} Backup to {} failed: {}", job.name, target.name, e),
+        }
+        (target.name.clone(), result)
+    }))
+    .await;
+    let failed = results.iter().filter(|(_, r)| r.as_ref().is_err_and(|e| !matches!(e, BackupError::Cancelled))).count();
+    if failed > 0 {
+        error!("{} Backup on {} failed for {} of {} repositories.", job.name, job.paths_label(), failed, results.len());
+    }
+    results
+}
+
+/// Backs up to one repository, retrying transient failures as the job's
+/// retry policy allows.
+async fn backup_with_retry(job: &BackupJobConfig, config: &BackupConfig, ctx: &Context) -> Result<BackupReport, BackupError> {
+    let policy = match &job.retry {
+        Some(policy) => policy,
+        None => return backup_to(job, config, ctx).await,
+    };
+    let mut shutdown = ctx.shutdown.clone();
+    let mut attempt = 1;
+    loop {
+        let result = backup_to(job, config, ctx).await;
+        let e = match result {
+            Ok(report) => {
+                if attempt > 1 {
+                    info!("{} Backup to {} succeeded on attempt {} of {}.", job.name, config.name, attempt, policy.max_attempts);
+                }
+                return Ok(report);
+            }
+            Err(e) => e,
+        };
+        let class = FailureClass::of(&e);
+        if !policy.retries(class) {
+            if attempt > 1 {
+                warn!("{} Backup to {} attempt {} of {} failed ({}), which is not retried.", job.name, config.name, attempt, policy.max_attempts, class);
+            }
+            return Err(e);
+        }
+        if attempt >= policy.max_attempts {
+            if policy.max_attempts > 1 {
+                warn!("{} Backup to {} giving up after {} attempts ({}).", job.name, config.name, attempt, class);
+            }
+            return Err(e);
+        }
+        let delay = policy.delay(attempt);
+        warn!(
+            "{} Backup to {} attempt {} of {} failed ({}): {}; retrying in {}.",
+            job.name,
+            config.name,
+            attempt,
+            policy.max_attempts,
+            class,
+            e,
+            format_duration(delay.as_secs_f64().ceil() as u64)
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = shutdown.reached(Phase::Stopping) => {
+                info!("{} Backup to {} will not be retried due to shutdown.", job.name, config.name);
+                return Err(e);
+            }
+        }
+        attempt += 1;
+    }
+}
+
+async fn backup_to(job: &BackupJobConfig, config: &BackupConfig, ctx: &Context) -> Result<BackupReport, BackupError> {
+    let label = format!("{} Backup to {}", job.name, config.name);
+    let mut shutdown = ctx.shutdown.clone();
+    let admitted = async {
+        let slot = ctx.scheduler.backup_slot(&label, config).await;
+        let shared = ctx.repos.shared(&config.repo).await;
+        let permit = ctx.scheduler.process_slot(&label).await;
+        (slot, shared, permit)
+    };
+    let (_slot, mut shared, mut permit) = tokio::select! {
+        admitted = admitted => admitted,
+        _ = shutdown.reached(Phase::Stopping) => return Err(BackupError::Cancelled),
+    };
+    if shutdown.phase() != Phase::Running {
+        return Err(BackupError::Cancelled);
+    }
+    info!("{} Backup on {} to {} initiating.", job.name, job.paths_label(), config.name);
+    let mut progress = ctx.status.track(job, config);
+    let parent = match snapshots::latest(job, config).await {
+        Ok(parent) => parent.map(|s| {
+            debug!("{} Backup to {}: using parent snapshot {} from {}.", job.name, config.name, s.short_id, s.time);
+            s.id
+        }),
+        Err(e) if init::is_missing(&e) => None,
+        Err(e) => {
+            warn!("{} Backup to {}: unable to look up the parent snapshot, restic will pick one: {}", job.name, config.name, e);
+            None
+        }
+    };
+    let parent = parent.as_deref();
+    let mut result = restic::backup(job, config, parent, &mut progress, &ctx.shutdown).await;
+    if config.init_if_missing && result.as_ref().is_err_and(init::is_missing) {
+        // Another job may be initializing the same repository, so probe
+        // again once nothing else is using it. The process slot is given
+        // up meanwhile so that waiting backups cannot block the exclusive access.
+        drop(permit);
+        drop(shared);
+        let created = {
+            let _exclusive = ctx.repos.exclusive(&config.repo).await;
+            init::init_if_missing(config).await
+        };
+        shared = ctx.repos.shared(&config.repo).await;
+        permit = ctx.scheduler.process_slot(&label).await;
+        match created {
+            Ok(_) => {
+                info!("{} Backup to {} retrying after initializing the repository.", job.name, config.name);
+                result = restic::backup(job, config, parent, &mut progress, &ctx.shutdown).await;
+            }
+            Err(e) => error!("{} Backup to {}: failed to initialize repository: {}", job.name, config.name, e),
+        }
+    }
+    let _held = (shared, permit);
+    if !matches!(result, Err(BackupError::Exit { code: Some(restic::EXIT_LOCKED), .. })) {
+        return result;
+    }
+    warn!("{} Backup to {}: repository is locked, checking for stale locks.", job.name, config.name);
+    let cleared = locks::clear_stale_locks(config).await;
+    if let Ok(removed) = cleared {
+        ctx.state.record_unlock(&config.name, removed);
+    }
+    match cleared {
+        Ok(0) => result,
+        Ok(_) => {
+            info!("{} Backup to {} retrying after removing stale locks.", job.name, config.name);
+            restic::backup(job, config, parent, &mut progress, &ctx.shutdown).await
+        }
+        Err(e) => {
+            error!("{} Backup to {}: failed to inspect locks: {}", job.name, config.name, e);
+            result
+        }
+    }
+}
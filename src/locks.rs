@@ -0,0 +1,278 @@
+//! Inspection and removal of stale repository locks.
+//!
+//! restic can only remove locks wholesale: `restic unlock` removes the locks
+//! restic itself considers stale, and `--remove-all` removes every lock. So
+//! each lock is inspected first, and a removal is only issued when
+//! everything it would delete is stale by the configured policy:
+//!
+//! * the lock is older than `stale-lock-age`, or
+//! * it was created on this host by a process that is no longer running.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Deserialize;
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+use crate::config::BackupConfig;
+use crate::restic::{self, BackupError};
+
+/// Age after which restic's own `unlock` treats a lock as stale.
+const RESTIC_STALE_AGE: i64 = 30 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct Lock {
+    pub time: DateTime<FixedOffset>,
+    #[serde(default)]
+    pub exclusive: bool,
+    #[serde(default)]
+    pub hostname: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub pid: i32,
+}
+
+impl Lock {
+    fn age(&self) -> i64 {
+        (Utc::now() - self.time.with_timezone(&Utc)).num_seconds()
+    }
+
+    fn owner_dead(&self, host: &str) -> bool {
+        self.hostname == host && !process_alive(self.pid)
+    }
+
+    /// Why the configured policy allows removing this lock, if it does.
+    fn stale_reason(&self, max_age: u64, host: &str) -> Option<String> {
+        if self.age() > max_age as i64 {
+            Some(format!("older than {}s", max_age))
+        } else if self.owner_dead(host) {
+            Some(format!("process {} on this host is no longer running", self.pid))
+        } else {
+            None
+        }
+    }
+
+    /// Mirrors restic's own staleness check used by plain `restic unlock`.
+    fn restic_considers_stale(&self, host: &str) -> bool {
+        self.age() > RESTIC_STALE_AGE || self.owner_dead(host)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} lock held by {}@{} (pid {}) since {}",
+            if self.exclusive { "exclusive" } else { "shared" },
+            self.username,
+            self.hostname,
+            self.pid,
+            self.time.to_rfc3339()
+        )
+    }
+}
+
+/// Removes the repository's stale locks and returns how many were removed.
+pub async fn clear_stale_locks(config: &BackupConfig) -> Result<usize, BackupError> {
+    let host = restic::hostname();
+    let (locks, complete) = list_locks(config).await?;
+    if locks.is_empty() && complete {
+        info!("{}: no locks present.", config.name);
+        return Ok(0);
+    }
+
+    let mut stale = vec![];
+    for (id, lock) in &locks {
+        match lock.stale_reason(config.stale_lock_age, &host) {
+            Some(reason) => stale.push((id, format!("{}: {}", lock.describe(), reason))),
+            None => info!("{}: keeping lock {}, {}.", config.name, short(id), lock.describe()),
+        }
+    }
+    if stale.is_empty() {
+        return Ok(0);
+    }
+
+    let mut cmd = restic::command(config);
+    cmd.arg("unlock");
+    let ids: HashSet<&str> = stale.iter().map(|(id, _)| id.as_str()).collect();
+    match unlock_mode(&locks, complete, &ids, &host) {
+        Unlock::RemoveAll => {
+            cmd.arg("--remove-all");
+        }
+        Unlock::Plain => {}
+        Unlock::Refuse => {
+            warn!(
+                "{}: not removing {} stale lock(s) because restic would also remove locks that are still in use.",
+                config.name,
+                stale.len()
+            );
+            return Ok(0);
+        }
+    }
+    restic::output(cmd).await?;
+
+    let remaining: HashSet<String> = list_ids(config).await?.into_iter().collect();
+    let mut removed = 0;
+    for (id, reason) in stale {
+        if remaining.contains(id.as_str()) {
+            warn!("{}: stale lock {} could not be removed yet ({}).", config.name, short(id), reason);
+        } else {
+            removed += 1;
+            info!("{}: removed lock {}, {}.", config.name, short(id), reason);
+        }
+    }
+    Ok(removed)
+}
+
+/// How `restic unlock` may be run to remove the `stale` locks.
+#[derive(Debug, PartialEq)]
+enum Unlock {
+    /// Every lock is stale, so `--remove-all` removes exactly those.
+    RemoveAll,
+    /// Plain `unlock` removes only locks that are stale by our policy too.
+    Plain,
+    /// Any removal would also take locks that are still in use.
+    Refuse,
+}
+
+fn unlock_mode(locks: &[(String, Lock)], complete: bool, stale: &HashSet<&str>, host: &str) -> Unlock {
+    if complete && stale.len() == locks.len() {
+        Unlock::RemoveAll
+    } else if !complete || locks.iter().any(|(id, lock)| lock.restic_considers_stale(host) && !stale.contains(id.as_str())) {
+        Unlock::Refuse
+    } else {
+        Unlock::Plain
+    }
+}
+
+async fn list_ids(config: &BackupConfig) -> Result<Vec<String>, BackupError> {
+    let mut cmd = restic::command(config);
+    cmd.arg("--no-lock").arg("list").arg("locks");
+    let output = restic::output(cmd).await?;
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(str::trim).filter(|l| !l.is_empty()).map(str::to_owned).collect())
+}
+
+/// Reads every lock in the repository. The flag is false if some lock could
+/// not be parsed, in which case it must not be removed blindly.
+async fn list_locks(config: &BackupConfig) -> Result<(Vec<(String, Lock)>, bool), BackupError> {
+    let mut locks = vec![];
+    let mut complete = true;
+    for id in list_ids(config).await? {
+        let mut cmd = restic::command(config);
+        cmd.arg("--no-lock").arg("cat").arg("lock").arg(&id);
+        // A lock can disappear between listing and reading it.
+        let output = match restic::output(cmd).await {
+            Ok(output) => output,
+            Err(BackupError::Exit { .. }) => continue,
+            Err(e) => return Err(e),
+        };
+        match serde_json::from_slice::<Lock>(&output.stdout) {
+            Ok(lock) => locks.push((id, lock)),
+            Err(e) => {
+                warn!("{}: unable to parse lock {}: {}", config.name, short(&id), e);
+                complete = false;
+            }
+        }
+    }
+    Ok((locks, complete))
+}
+
+fn short(id: &str) -> &str {
+    &id[..id.len().min(8)]
+}
+
+fn process_alive(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    // SAFETY: signal 0 only checks whether the process exists.
+    let rc = unsafe { libc::kill(pid, 0) };
+    rc == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// In-process counterpart of restic's repository locks: backups share a
+/// repository, while prune and check need it to themselves.
+#[derive(Default)]
+pub struct RepoGuards {
+    repos: std::sync::Mutex<HashMap<String, Arc<RwLock<()>>>>,
+}
+
+impl RepoGuards {
+    fn get(&self, repo: &str) -> Arc<RwLock<()>> {
+        self.repos.lock().unwrap().entry(repo.to_owned()).or_default().clone()
+    }
+
+    pub async fn shared(&self, repo: &str) -> OwnedRwLockReadGuard<()> {
+        self.get(repo).read_owned().await
+    }
+
+    pub async fn exclusive(&self, repo: &str) -> OwnedRwLockWriteGuard<()> {
+        self.get(repo).write_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "backup-host";
+
+    fn lock(age: i64, hostname: &str, pid: i32) -> Lock {
+        Lock {
+            time: (Utc::now() - chrono::Duration::seconds(age)).fixed_offset(),
+            exclusive: false,
+            hostname: hostname.to_owned(),
+            username: "root".to_owned(),
+            pid,
+        }
+    }
+
+    /// A pid that cannot belong to a running process.
+    const DEAD_PID: i32 = i32::MAX;
+
+    #[test]
+    fn old_locks_are_stale() {
+        assert_eq!(lock(7200, "elsewhere", 1).stale_reason(3600, HOST).as_deref(), Some("older than 3600s"));
+        assert_eq!(lock(600, "elsewhere", 1).stale_reason(3600, HOST), None);
+    }
+
+    #[test]
+    fn locks_of_dead_local_processes_are_stale() {
+        let reason = lock(60, HOST, DEAD_PID).stale_reason(3600, HOST);
+        assert_eq!(reason, Some(format!("process {} on this host is no longer running", DEAD_PID)));
+        assert_eq!(lock(60, HOST, std::process::id() as i32).stale_reason(3600, HOST), None);
+        // The same pid on another host says nothing about that process.
+        assert_eq!(lock(60, "elsewhere", DEAD_PID).stale_reason(3600, HOST), None);
+    }
+
+    #[test]
+    fn restic_uses_its_own_age() {
+        assert!(lock(RESTIC_STALE_AGE + 60, "elsewhere", 1).restic_considers_stale(HOST));
+        assert!(!lock(RESTIC_STALE_AGE - 60, "elsewhere", 1).restic_considers_stale(HOST));
+        assert!(lock(60, HOST, DEAD_PID).restic_considers_stale(HOST));
+    }
+
+    #[test]
+    fn remove_all_only_when_every_lock_is_stale() {
+        let locks = vec![("a".to_owned(), lock(7200, "elsewhere", 1)), ("b".to_owned(), lock(60, HOST, DEAD_PID))];
+        let all: HashSet<&str> = ["a", "b"].into();
+        assert_eq!(unlock_mode(&locks, true, &all, HOST), Unlock::RemoveAll);
+        // A lock that could not be read might be in use.
+        assert_eq!(unlock_mode(&locks, false, &all, HOST), Unlock::Refuse);
+    }
+
+    #[test]
+    fn plain_unlock_when_restic_agrees_on_what_is_stale() {
+        let locks = vec![("a".to_owned(), lock(7200, "elsewhere", 1)), ("b".to_owned(), lock(60, "elsewhere", 1))];
+        assert_eq!(unlock_mode(&locks, true, &["a"].into(), HOST), Unlock::Plain);
+    }
+
+    #[test]
+    fn refuse_when_restic_would_remove_a_lock_we_keep() {
+        // Stale to restic after 30 minutes, but kept by a two-hour policy.
+        let locks = vec![("a".to_owned(), lock(2 * 3600 + 60, "elsewhere", 1)), ("b".to_owned(), lock(3600, "elsewhere", 1))];
+        let stale: HashSet<&str> =
+            locks.iter().filter(|(_, lock)| lock.stale_reason(2 * 3600, HOST).is_some()).map(|(id, _)| id.as_str()).collect();
+        assert_eq!(stale, ["a"].into());
+        assert_eq!(unlock_mode(&locks, true, &stale, HOST), Unlock::Refuse);
+    }
+}
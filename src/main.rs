@@ -1,8 +1,35 @@
-use std::{path::Path, io::Read};
-use notify::{RecursiveMode, Watcher};
-use tokio;
-use std::io::BufReader;
-use serde_json::{Value};
+mod catchup;
+mod check;
+mod config;
+mod env;
+mod history;
+mod hooks;
+mod init;
+mod job;
+mod locks;
+mod maintenance;
+mod messages;
+mod options;
+mod restic;
+mod retention;
+mod retry;
+mod schedule;
+mod scheduler;
+mod secrets;
+mod shutdown;
+mod snapshots;
+mod state;
+mod status;
+mod watcher;
+use config::BackupConfig;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use job::{Context, Job, Trigger};
+use shutdown::Phase;
+use state::StateStore;
+use status::StatusBoard;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[macro_use] extern crate log;
 extern crate simplelog;
@@ -10,133 +37,162 @@ extern crate simplelog;
 use simplelog::*;
 use std::fs::File;
 
-#[derive(Clone)]
-struct BackupConfig {
-    repo: String,
-    exclude_file: String,
-    password_command: String,
-    logfile: String,
-    env_path: String,
-    restic_path: String
-}
-#[derive(Clone)]
-struct BackupJobConfig {
-    name: String,
-    path: String,
-    throttle: u64
+async fn initialize_repository(config:&BackupConfig) {
+    if let Err(e) = init::init_if_missing(config).await {
+        error!("Failed to initialize {}: {}",config.name,e);
+    }
 }
 
-async fn backup(job:&BackupJobConfig,config:&BackupConfig) -> Result<(),()>{
-    info!("FS Changes detected on {}, backup scheduled in {} seconds.",job.path,job.throttle);
-    tokio::time::sleep(std::time::Duration::from_secs(job.throttle)).await;
-    info!("{} Backup on {} initiating.",job.name,job.path);
-    let job = job.clone();
-    let config = config.clone();
-    let mut cmd = std::process::Command::new(config.restic_path)
-        .env("PATH",config.env_path)
-        .env("RESTIC_PASSWORD_COMMAND",config.password_command)
-        .arg("-r")
-        .arg(config.repo)
-        .arg("--json")
-        .arg("-q")
-        .arg("--exclude-file")
-        .arg(config.exclude_file)
-        .arg("backup")
-        .arg(job.path)
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn().expect("Failed to spawn restic process.");
+async fn unlock_repository(config:&BackupConfig,state:&StateStore) {
+    info!("Checking {} for stale locks",config.name);
+    match locks::clear_stale_locks(config).await {
+        Ok(removed) => state.record_unlock(&config.name,removed),
+        Err(e) => error!("Failed to inspect locks on {}: {}",config.name,e),
+    }
+}
 
-    let mut reader = BufReader::new(cmd.stdout.take().unwrap());
-    let mut err_reader = BufReader::new(cmd.stderr.take().expect("No err captured"));
-    
-    let mut result = String::new();
-    if reader.read_to_string(&mut result).is_err() {error!("Unable to parse response from restic.");}
-    cmd.wait();
-    match serde_json::from_str::<Value>(&result) {
-        Ok(v) => {
-            info!("Backup Complete. - {} new, {} changed, finished in {} seconds.", v["files_new"], v["files_changed"], v["total_duration"]);
-        },
-        Err(_) => {
-            error!("Unable to parse restic response json: Raw resp: {}",result);
+/// `history` subcommand: prints the run history and returns the exit code.
+fn show_history_file(args:impl Iterator<Item = String>) -> i32 {
+    let (filter,rest) = match history::Filter::parse(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
         }
     };
-    
-    Ok(())
-}
-
-async fn start_watching(job:BackupJobConfig,config:&BackupConfig) {
-    let (tx,mut rx) = tokio::sync::mpsc::unbounded_channel::<String>();
-    let mut watcher = notify::recommended_watcher(move |res| {
-        match res {
-           Ok(_) => {tx.send("hit".to_owned()).expect("Failed to send message over channel.");},
-           Err(_) => {}
+    let config_path = rest.into_iter().next().unwrap_or("config.yml".to_owned());
+    let file = match config::Config::load_history_file(&config_path) {
+        Ok(Some(file)) => file,
+        Ok(None) => {
+            eprintln!("{} does not configure a history-file", config_path);
+            return 2;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
         }
-    }).unwrap();
-    watcher.watch(Path::new(&job.path), RecursiveMode::Recursive).expect(&format!("Failed to start watching on path {}",&job.path));
-    info!("Started FSEvent monitoring on {} named {} - interval={}",&job.path,&job.name,&job.throttle);
-    loop {
-        rx.recv().await.unwrap();
-        tokio::select! {
-            _ = backup(&job,config) => {},
-            _ = async {
-                loop {
-                    rx.recv().await;
-                }
-            } => {}
+    };
+    match history::print(&file,&filter) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
         }
     }
 }
 
-async fn unlock_repository(config:&BackupConfig) {
-    info!("Unlocking Repository");
-    let config = config.clone();
-    info!("Attempting to remove stale lock");
-    std::process::Command::new(config.restic_path)
-        .env("PATH",config.env_path)
-        .env("RESTIC_PASSWORD_COMMAND",config.password_command)
-        .arg("-r")
-        .arg(config.repo)
-        .arg("unlock")
-        .stdout(std::process::Stdio::inherit())
-        .stderr(std::process::Stdio::inherit())
-        .spawn().expect("Failed to spawn restic process.").wait().expect("Failed to remove stale lock.");
-    info!("Lock removed success.")
-}
-
 #[tokio::main]
 async fn main() {
-    let config_path = std::env::args().nth(1).unwrap_or("config.yml".to_owned());
-    let f= std::fs::read_to_string(config_path).expect("Failed to read config file.");
-    let y  = yaml_rust::YamlLoader::load_from_str(&f).expect("Failed to parse yaml");
-    
-    let config = BackupConfig {
-        repo: y[0]["repo"].as_str().expect("Failed to parse repo from config").to_owned(),
-        exclude_file: y[0]["exclude-file"].as_str().unwrap().to_owned(),
-        password_command: y[0]["password-command"].as_str().unwrap().to_owned(),
-        logfile: y[0]["logfile"].as_str().unwrap().to_owned(),
-        env_path: y[0]["env-path"].as_str().unwrap().to_owned(),
-        restic_path: y[0]["restic-path"].as_str().unwrap().to_owned()
+    let mut args = std::env::args().skip(1).peekable();
+    let check_only = matches!(args.peek().map(String::as_str), Some("check-config") | Some("dry-run"));
+    let show_history = args.peek().map(String::as_str) == Some("history");
+    if check_only || show_history {
+        args.next();
+    }
+    if show_history {
+        std::process::exit(show_history_file(args));
+    }
+    let config_path = args.next().unwrap_or("config.yml".to_owned());
+    let loaded = match config::Config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
     };
+    if check_only {
+        std::process::exit(if check::run(&loaded) { 0 } else { 1 });
+    }
+    let config = loaded;
 
     // Configure Logging
     let term_logger = TermLogger::new(LevelFilter::Info, Config::default(), TerminalMode::Mixed, ColorChoice::Auto);
     let write_logger = WriteLogger::new(LevelFilter::Info, Config::default(), File::create(&config.logfile).expect("Unable to create logfile."));
     CombinedLogger::init(vec![term_logger,write_logger]).unwrap();
 
-    unlock_repository(&config).await;
+    let state = StateStore::open(config.state_dir.clone());
+    for job in &config.dirs {
+        state.report(job);
+    }
+    for repo in config.repositories() {
+        if repo.init_if_missing {
+            initialize_repository(repo).await;
+        }
+        unlock_repository(repo,&state).await;
+    }
+
+    let (trigger,shutdown) = shutdown::channel();
+    let ctx = Context { status: StatusBoard::new(config.status_file.clone(),state.clone()), state, history: history::History::new(config.history_file.clone()),
+        shutdown, repos: Default::default(),
+        scheduler: scheduler::Scheduler::new(config.max_restic_processes,&config.repositories()) };
+    let mut dirs: Vec<BoxFuture<()>> = vec![];
 
-    let mut dirs = vec![];
+    for repo in config.repositories() {
+        if let Some(check) = repo.check.clone() {
+            let (checker,task_ctx) = (Arc::new(maintenance::Checker::new(repo.clone(),check,ctx.state.repository(&repo.name).next_check_subset)),ctx.clone());
+            dirs.push(schedule::run_schedule(format!("check of {}",repo.name),checker.check.schedule.clone(),ctx.clone(),move || {
+                let (checker,ctx) = (checker.clone(),task_ctx.clone());
+                async move { checker.run(&ctx).await }
+            }).boxed());
+        }
+    }
 
-    for dir in y[0]["dirs"].as_vec().unwrap() {
-            dirs.push(
-                start_watching(BackupJobConfig {
-                    name: dir["name"].as_str().unwrap().to_owned(),
-                    path: dir["path"].as_str().unwrap().to_owned(),
-                    throttle: dir["throttle"].as_i64().unwrap() as u64
-                },&config)
-            )
+    for job in config.dirs {
+        let job = Job::new(job);
+        if job.config.catch_up.is_some() {
+            dirs.push(catchup::run(job.clone(),ctx.clone()).boxed());
+        }
+        if let Some(schedule) = job.config.schedule.clone() {
+            let (job,task_ctx) = (job.clone(),ctx.clone());
+            dirs.push(schedule::run_schedule(format!("{} backup of {}",job.config.name,job.config.paths_label()),schedule,ctx.clone(),move || {
+                let (job,ctx) = (job.clone(),task_ctx.clone());
+                async move { let _ = job.run(Trigger::Schedule,&ctx).await; }
+            }).boxed());
+        }
+        if let Some(schedule) = job.config.retention.as_ref().and_then(|r| r.schedule.clone()) {
+            let (job,task_ctx) = (job.clone(),ctx.clone());
+            dirs.push(schedule::run_schedule(format!("{} retention",job.config.name),schedule,ctx.clone(),move || {
+                let (job,ctx) = (job.clone(),task_ctx.clone());
+                async move { job.apply_retention(&ctx).await }
+            }).boxed());
+        }
+        if job.config.watch {
+            dirs.push(watcher::start_watching(job,ctx.clone()).boxed());
+        }
     }
 
-    futures::future::join_all(dirs).await;
+    let watchers = futures::future::join_all(dirs);
+    tokio::pin!(watchers);
+    let signal = tokio::select! {
+        _ = &mut watchers => return,
+        signal = shutdown::signal_received() => signal,
+    };
+
+    let started = Instant::now();
+    let running = ctx.status.running();
+    info!("Received {}, no new backups will start. Waiting up to {}s for {} running backup(s){}{}",
+        signal, config.shutdown_grace, running.len(), if running.is_empty() { "" } else { ": " }, running.join(", "));
+    trigger.set(Phase::Stopping);
+
+    let mut interrupted = vec![];
+    let finished = tokio::select! {
+        _ = &mut watchers => true,
+        _ = tokio::time::sleep(Duration::from_secs(config.shutdown_grace)) => {
+            interrupted = ctx.status.running();
+            warn!("Shutdown grace period expired, interrupting {} backup(s): {}", interrupted.len(), interrupted.join(", "));
+            false
+        },
+        signal = shutdown::signal_received() => {
+            interrupted = ctx.status.running();
+            warn!("Received second {}, interrupting {} backup(s): {}", signal, interrupted.len(), interrupted.join(", "));
+            false
+        },
+    };
+    if !finished {
+        // Also interrupts checks, forgets and prunes still running.
+        trigger.set(Phase::Aborting);
+        watchers.await;
+    }
+    info!("Shutdown complete after {}s: {} backup(s) finished, {} interrupted.",
+        started.elapsed().as_secs(), running.len().saturating_sub(interrupted.len()), interrupted.len());
 }
\ No newline at end of file
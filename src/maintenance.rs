@@ -0,0 +1,86 @@
+//! Periodic repository integrity checks with `restic check`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde::Deserialize;
+
+use crate::config::BackupConfig;
+use crate::job::Context;
+use crate::restic::{self, BackupError};
+use crate::schedule::Schedule;
+use crate::shutdown::Phase;
+
+/// A repository's `check:` block.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CheckConfig {
+    pub schedule: Schedule,
+    /// Percentage of pack data to read on each run, e.g. `10%`. Successive
+    /// runs read successive subsets so the whole repository is covered
+    /// every `100 / N` runs.
+    #[serde(default, deserialize_with = "percentage")]
+    pub read_data_subset: Option<u32>,
+}
+
+fn percentage<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+    let value = String::deserialize(d)?;
+    match value.trim().trim_end_matches('%').parse::<u32>() {
+        Ok(n) if (1..=100).contains(&n) => Ok(Some(n)),
+        _ => Err(serde::de::Error::custom(format!("expected a percentage between 1% and 100%, got '{}'", value))),
+    }
+}
+
+/// Runs the repository checks for one repository, rotating through data
+/// subsets across runs and restarts.
+pub struct Checker {
+    pub config: BackupConfig,
+    pub check: CheckConfig,
+    next_subset: AtomicU32,
+}
+
+impl Checker {
+    /// `next_subset` is the subset index to resume from, counting from 0.
+    pub fn new(config: BackupConfig, check: CheckConfig, next_subset: u32) -> Checker {
+        Checker { config, check, next_subset: AtomicU32::new(next_subset) }
+    }
+
+    /// Number of subsets the repository is split into for `--read-data-subset`.
+    fn subsets(&self) -> Option<u32> {
+        self.check.read_data_subset.map(|pct| 100u32.div_ceil(pct))
+    }
+
+    /// Runs `restic check` with exclusive access to the repository, logging
+    /// failures like failed backups. Nothing runs once shutdown has started,
+    /// and restic is interrupted if shutdown reaches `Aborting`.
+    pub async fn run(&self, ctx: &Context) {
+        let label = format!("Check of {}", self.config.name);
+        let _exclusive = ctx.repos.exclusive(&self.config.repo).await;
+        let _permit = ctx.scheduler.process_slot(&label).await;
+        // Shutdown may have started while waiting for the repository.
+        if ctx.shutdown.phase() != Phase::Running {
+            info!("{} skipped due to shutdown.", label);
+            return;
+        }
+        let mut cmd = restic::command(&self.config);
+        cmd.arg("check");
+        if let Some(n) = self.subsets() {
+            let k = self.next_subset.load(Ordering::Relaxed) % n + 1;
+            info!("{} initiating, reading data subset {}/{}.", label, k, n);
+            cmd.arg(format!("--read-data-subset={}/{}", k, n));
+        } else {
+            info!("{} initiating.", label);
+        }
+        let result = restic::interruptible_output(cmd, &label, &ctx.shutdown).await.map(|_| ());
+        if result.is_ok() {
+            // A failed check reads the same subset again next time. Runs
+            // never overlap because each holds the repository exclusively.
+            self.next_subset.fetch_add(1, Ordering::Relaxed);
+        }
+        match &result {
+            Ok(()) => info!("{} Complete. - no errors found.", label),
+            Err(e @ BackupError::Exit { code: Some(1), .. }) => error!("{} failed: repository has errors: {}", label, e),
+            Err(e) => error!("{} failed: {}", label, e),
+        }
+        ctx.state.record_check(&self.config.name, self.next_subset.load(Ordering::Relaxed), &result);
+    }
+}
@@ -0,0 +1,214 @@
+//! Typed `restic backup --json` messages and the incremental parser that
+//! turns restic's newline-delimited output into a `BackupReport`.
+//!
+//! Fields default when absent so that output from older and newer restic
+//! releases both parse.
+
+use serde::{Deserialize, Serialize};
+
+use crate::restic::BackupError;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "message_type", rename_all = "snake_case")]
+pub enum BackupMessage {
+    Status(Status),
+    VerboseStatus(VerboseStatus),
+    Error(ErrorMessage),
+    Summary(Summary),
+    ExitError(ExitError),
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Status {
+    pub seconds_elapsed: u64,
+    pub seconds_remaining: u64,
+    pub percent_done: f64,
+    pub total_files: u64,
+    pub files_done: u64,
+    pub total_bytes: u64,
+    pub bytes_done: u64,
+    pub error_count: u64,
+    pub current_files: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct VerboseStatus {
+    pub action: String,
+    pub item: String,
+    pub duration: f64,
+    pub data_size: u64,
+    pub data_size_in_repo: u64,
+    pub metadata_size: u64,
+    pub metadata_size_in_repo: u64,
+    pub total_files: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ErrorMessage {
+    pub error: ErrorDetail,
+    pub during: String,
+    pub item: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ErrorDetail {
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Summary {
+    pub files_new: u64,
+    pub files_changed: u64,
+    pub files_unmodified: u64,
+    pub dirs_new: u64,
+    pub dirs_changed: u64,
+    pub dirs_unmodified: u64,
+    pub data_blobs: i64,
+    pub tree_blobs: i64,
+    pub data_added: u64,
+    pub data_added_packed: u64,
+    pub total_files_processed: u64,
+    pub total_bytes_processed: u64,
+    pub total_duration: f64,
+    pub snapshot_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ExitError {
+    pub code: i32,
+    pub message: String,
+}
+
+/// Result of a successful backup to one repository.
+#[derive(Clone, Debug)]
+pub struct BackupReport {
+    pub summary: Summary,
+    /// Number of per-file errors restic reported while backing up.
+    pub errors: usize,
+}
+
+/// Consumes restic's stdout one line at a time.
+pub struct BackupOutput {
+    label: String,
+    summary: Option<Summary>,
+    errors: usize,
+    unparsed: Vec<String>,
+    parse_error: Option<String>,
+}
+
+impl BackupOutput {
+    /// `label` prefixes every log line, e.g. "home Backup to local".
+    pub fn new(label: String) -> BackupOutput {
+        BackupOutput { label, summary: None, errors: 0, unparsed: vec![], parse_error: None }
+    }
+
+    /// Handles one line of output and returns the decoded message, if any.
+    pub fn line(&mut self, line: &str) -> Option<BackupMessage> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        let message = match serde_json::from_str::<BackupMessage>(line) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("{}: unexpected restic output: {}", self.label, line);
+                self.parse_error = Some(e.to_string());
+                self.unparsed.push(line.to_owned());
+                return None;
+            }
+        };
+        match &message {
+            BackupMessage::Error(e) => {
+                self.errors += 1;
+                warn!("{}: error during {} of {}: {}", self.label, e.during, e.item, e.error.message);
+            }
+            BackupMessage::ExitError(e) => error!("{}: restic exit error {}: {}", self.label, e.code, e.message),
+            BackupMessage::Summary(summary) => self.summary = Some(summary.clone()),
+            BackupMessage::VerboseStatus(v) => debug!("{}: {} {}", self.label, v.action, v.item),
+            BackupMessage::Status(s) => debug!("{}: {:.1}% done", self.label, s.percent_done * 100.0),
+            BackupMessage::Unknown => {}
+        }
+        Some(message)
+    }
+
+    /// Returns the report once restic has exited successfully.
+    pub fn finish(self) -> Result<BackupReport, BackupError> {
+        match self.summary {
+            Some(summary) => Ok(BackupReport { summary, errors: self.errors }),
+            None => Err(BackupError::Parse {
+                message: self.parse_error.unwrap_or_else(|| "no summary message in restic output".to_owned()),
+                output: self.unparsed.join("\n"),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output() -> BackupOutput {
+        BackupOutput::new("home Backup to local".to_owned())
+    }
+
+    const SUMMARY: &str = r#"{"message_type":"summary","files_new":3,"files_changed":1,"files_unmodified":10,"dirs_new":1,"dirs_changed":0,"dirs_unmodified":4,"data_blobs":5,"tree_blobs":2,"data_added":2048,"data_added_packed":1024,"total_files_processed":14,"total_bytes_processed":4096,"total_duration":1.5,"snapshot_id":"0123456789abcdef"}"#;
+
+    #[test]
+    fn status_lines_are_decoded() {
+        let mut output = output();
+        let line = r#"{"message_type":"status","percent_done":0.25,"total_files":8,"files_done":2,"current_files":["/home/a"]}"#;
+        match output.line(line) {
+            Some(BackupMessage::Status(status)) => {
+                assert_eq!(status.percent_done, 0.25);
+                assert_eq!(status.files_done, 2);
+                assert_eq!(status.current_files, ["/home/a"]);
+            }
+            other => panic!("expected a status message, got {:?}", other),
+        }
+        assert!(output.line("  ").is_none());
+    }
+
+    #[test]
+    fn file_errors_are_counted() {
+        let mut output = output();
+        let line = r#"{"message_type":"error","error":{"message":"permission denied"},"during":"archival","item":"/home/secret"}"#;
+        assert!(matches!(output.line(line), Some(BackupMessage::Error(e)) if e.item == "/home/secret"));
+        output.line(SUMMARY);
+        assert_eq!(output.finish().unwrap().errors, 1);
+    }
+
+    #[test]
+    fn summary_becomes_the_report() {
+        let mut output = output();
+        output.line(r#"{"message_type":"verbose_status","action":"new","item":"/home/a"}"#);
+        output.line(SUMMARY);
+        let report = output.finish().unwrap();
+        assert_eq!(report.errors, 0);
+        let summary = report.summary;
+        assert_eq!((summary.files_new, summary.files_changed, summary.files_unmodified), (3, 1, 10));
+        assert_eq!(summary.data_added, 2048);
+        assert_eq!(summary.total_duration, 1.5);
+        assert_eq!(summary.snapshot_id.as_deref(), Some("0123456789abcdef"));
+    }
+
+    #[test]
+    fn missing_summary_is_a_parse_error() {
+        let mut output = output();
+        output.line(r#"{"message_type":"status","percent_done":1.0}"#);
+        output.line("Fatal: unable to open repository");
+        match output.finish() {
+            Err(BackupError::Parse { output, .. }) => assert_eq!(output, "Fatal: unable to open repository"),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        let empty = BackupOutput::new("empty".to_owned()).finish();
+        assert!(matches!(empty, Err(BackupError::Parse { message, .. }) if message == "no summary message in restic output"));
+    }
+}
@@ -0,0 +1,292 @@
+//! Per-job `restic backup` options and the argument list they render to.
+
+use serde::Deserialize;
+
+use crate::config::{BackupConfig, BackupJobConfig};
+use crate::env::PACK_SIZE_RANGE;
+use crate::restic;
+
+/// Options managed by the daemon itself, which `extra-args` must not override.
+const RESERVED_ARGS: [&str; 8] = ["-r", "--repo", "--repository-file", "--json", "-q", "--quiet", "--password-command", "--password-file"];
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Auto,
+    Off,
+    Max,
+}
+
+impl Compression {
+    fn as_str(self) -> &'static str {
+        match self {
+            Compression::Auto => "auto",
+            Compression::Off => "off",
+            Compression::Max => "max",
+        }
+    }
+}
+
+/// A job's options for `restic backup`. Each field maps to the restic flag
+/// of the same name.
+#[derive(Clone, Debug, Default)]
+pub struct BackupOptions {
+    /// Tags recorded on every snapshot. Defaults to the job name.
+    pub tags: Vec<String>,
+    /// Host name recorded instead of this machine's.
+    pub host: Option<String>,
+    pub exclude: Vec<String>,
+    pub iexclude: Vec<String>,
+    pub exclude_if_present: Vec<String>,
+    pub exclude_caches: bool,
+    /// Size such as `500M` or `2G`.
+    pub exclude_larger_than: Option<String>,
+    pub one_file_system: bool,
+    pub compression: Option<Compression>,
+    pub read_concurrency: Option<u32>,
+    /// Target pack size in MiB.
+    pub pack_size: Option<u32>,
+    /// Passed to restic verbatim, before the path.
+    pub extra_args: Vec<String>,
+}
+
+impl BackupOptions {
+    /// Problems with the option values, as (config key, message) pairs.
+    pub fn problems(&self) -> Vec<(&'static str, String)> {
+        let mut problems = vec![];
+        for tag in &self.tags {
+            if tag.is_empty() || tag.contains(',') {
+                problems.push(("tags", format!("invalid tag '{}': tags must be non-empty and must not contain commas", tag)));
+            }
+        }
+        if self.host.as_deref().is_some_and(|h| h.trim().is_empty()) {
+            problems.push(("host", "must not be empty".to_owned()));
+        }
+        if let Some(size) = &self.exclude_larger_than {
+            if !is_size(size) {
+                problems.push(("exclude-larger-than", format!("invalid size '{}', expected a number with an optional K, M, G or T suffix", size)));
+            }
+        }
+        if self.read_concurrency == Some(0) {
+            problems.push(("read-concurrency", "must be at least 1".to_owned()));
+        }
+        if let Some(size) = self.pack_size {
+            if !PACK_SIZE_RANGE.contains(&size) {
+                problems.push(("pack-size", format!("must be between {} and {} MiB", PACK_SIZE_RANGE.start(), PACK_SIZE_RANGE.end())));
+            }
+        }
+        for arg in &self.extra_args {
+            let flag = arg.split('=').next().unwrap_or(arg);
+            if RESERVED_ARGS.contains(&flag) {
+                problems.push(("extra-args", format!("{} is set by restic-automator and cannot be passed here", flag)));
+            }
+        }
+        problems
+    }
+
+    /// The host name snapshots are recorded under.
+    pub fn snapshot_host(&self) -> String {
+        self.host.clone().unwrap_or_else(restic::hostname)
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        let mut flag = |name: &str, value: &str| {
+            args.push(name.to_owned());
+            args.push(value.to_owned());
+        };
+        for tag in &self.tags {
+            flag("--tag", tag);
+        }
+        if let Some(host) = &self.host {
+            flag("--host", host);
+        }
+        for pattern in &self.exclude {
+            flag("--exclude", pattern);
+        }
+        for pattern in &self.iexclude {
+            flag("--iexclude", pattern);
+        }
+        for file in &self.exclude_if_present {
+            flag("--exclude-if-present", file);
+        }
+        if let Some(size) = &self.exclude_larger_than {
+            flag("--exclude-larger-than", size);
+        }
+        if let Some(compression) = self.compression {
+            flag("--compression", compression.as_str());
+        }
+        if let Some(n) = self.read_concurrency {
+            flag("--read-concurrency", &n.to_string());
+        }
+        if let Some(size) = self.pack_size {
+            flag("--pack-size", &size.to_string());
+        }
+        if self.exclude_caches {
+            args.push("--exclude-caches".to_owned());
+        }
+        if self.one_file_system {
+            args.push("--one-file-system".to_owned());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+/// Arguments following `-r <repo>` for backing `job` up to `config`.
+pub fn backup_args(job: &BackupJobConfig, config: &BackupConfig, parent: Option<&str>) -> Vec<String> {
+    let mut args = vec!["--json".to_owned()];
+    if !job.progress {
+        args.push("-q".to_owned());
+    }
+    args.push("backup".to_owned());
+    if let Some(exclude_file) = &config.exclude_file {
+        args.push("--exclude-file".to_owned());
+        args.push(exclude_file.clone());
+    }
+    if let Some(parent) = parent {
+        args.push("--parent".to_owned());
+        args.push(parent.to_owned());
+    }
+    args.extend(job.options.args());
+    args.extend(job.paths.iter().cloned());
+    args
+}
+
+fn is_size(size: &str) -> bool {
+    let digits = size.strip_suffix(['k', 'K', 'm', 'M', 'g', 'G', 't', 'T']).unwrap_or(size);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Loads a single job `home` backing up to `/srv/repo`, with `job` as
+    /// its remaining settings.
+    fn load(job: &str) -> (BackupJobConfig, BackupConfig) {
+        let text = format!(
+            "password-command: echo secret\nrepo: /srv/repo\nexclude-file: /etc/restic/excludes\ndirs:\n  - name: home\n{}",
+            job
+        );
+        let mut job = Config::parse("test.yml", &text).unwrap().dirs.remove(0);
+        let target = job.targets.remove(0);
+        (job, target)
+    }
+
+    fn problem_keys(options: &BackupOptions) -> Vec<&'static str> {
+        options.problems().into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn backup_args_default_order() {
+        let (job, target) = load("    path: /home\n");
+        assert_eq!(
+            backup_args(&job, &target, None),
+            ["--json", "-q", "backup", "--exclude-file", "/etc/restic/excludes", "--tag", "home", "/home"]
+        );
+    }
+
+    #[test]
+    fn backup_args_with_every_option() {
+        let (job, target) = load(concat!(
+            "    path: /home\n",
+            "    progress: true\n",
+            "    tags: [daily, laptop]\n",
+            "    host: laptop\n",
+            "    exclude: ['*.tmp']\n",
+            "    iexclude: ['*.ISO']\n",
+            "    exclude-if-present: [.nobackup]\n",
+            "    exclude-larger-than: 2G\n",
+            "    compression: max\n",
+            "    read-concurrency: 4\n",
+            "    pack-size: 64\n",
+            "    exclude-caches: true\n",
+            "    one-file-system: true\n",
+            "    extra-args: [--no-scan]\n",
+        ));
+        assert_eq!(
+            backup_args(&job, &target, Some("abc123")),
+            [
+                "--json",
+                "backup",
+                "--exclude-file",
+                "/etc/restic/excludes",
+                "--parent",
+                "abc123",
+                "--tag",
+                "daily",
+                "--tag",
+                "laptop",
+                "--host",
+                "laptop",
+                "--exclude",
+                "*.tmp",
+                "--iexclude",
+                "*.ISO",
+                "--exclude-if-present",
+                ".nobackup",
+                "--exclude-larger-than",
+                "2G",
+                "--compression",
+                "max",
+                "--read-concurrency",
+                "4",
+                "--pack-size",
+                "64",
+                "--exclude-caches",
+                "--one-file-system",
+                "--no-scan",
+                "/home",
+            ]
+        );
+    }
+
+    #[test]
+    fn backup_args_end_with_every_path() {
+        let (job, target) = load("    path: [/home, /etc]\n    extra-args: [--no-scan]\n");
+        assert!(backup_args(&job, &target, None).ends_with(&["--no-scan".to_owned(), "/home".to_owned(), "/etc".to_owned()]));
+    }
+
+    #[test]
+    fn valid_options_have_no_problems() {
+        let options = BackupOptions {
+            tags: vec!["daily".to_owned()],
+            host: Some("laptop".to_owned()),
+            exclude_larger_than: Some("500M".to_owned()),
+            read_concurrency: Some(2),
+            pack_size: Some(16),
+            extra_args: vec!["--no-scan".to_owned()],
+            ..Default::default()
+        };
+        assert!(options.problems().is_empty());
+    }
+
+    #[test]
+    fn invalid_options_are_reported_by_key() {
+        let options = BackupOptions {
+            tags: vec!["a,b".to_owned(), String::new()],
+            host: Some(" ".to_owned()),
+            exclude_larger_than: Some("2GB".to_owned()),
+            read_concurrency: Some(0),
+            pack_size: Some(256),
+            ..Default::default()
+        };
+        assert_eq!(problem_keys(&options), ["tags", "tags", "host", "exclude-larger-than", "read-concurrency", "pack-size"]);
+    }
+
+    #[test]
+    fn extra_args_cannot_override_daemon_options() {
+        for arg in ["--repo=/elsewhere", "-r", "--json", "--password-file=/tmp/pw"] {
+            let options = BackupOptions { extra_args: vec![arg.to_owned()], ..Default::default() };
+            assert_eq!(problem_keys(&options), ["extra-args"], "{}", arg);
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        assert!(is_size("100") && is_size("500k") && is_size("2G"));
+        assert!(!is_size("") && !is_size("G") && !is_size("1.5G") && !is_size("2GB"));
+    }
+}
@@ -0,0 +1,296 @@
+//! Construction and execution of restic invocations shared by the daemon
+//! and `check-config`.
+
+use std::fmt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::process::Child;
+
+use crate::config::{BackupConfig, BackupJobConfig};
+use crate::messages::{BackupMessage, BackupOutput, BackupReport};
+use crate::options;
+use crate::shutdown::{Phase, Shutdown};
+use crate::status::ProgressTracker;
+
+/// Returns a restic command for the configured repository with the
+/// password and credentials restic needs to open it. Callers append the
+/// subcommand.
+pub fn command(config: &BackupConfig) -> Command {
+    let mut cmd = Command::new(&config.restic_path);
+    config.env.apply(&mut cmd);
+    config.password.apply(&mut cmd);
+    config.credentials.apply(&mut cmd);
+    cmd.arg("-r").arg(&config.repo);
+    cmd
+}
+
+/// The host name restic records in snapshots and locks.
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its full length and gethostname
+    // writes at most that many bytes.
+    let rc = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if rc != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// The path as restic records it in a snapshot: absolute, without resolving
+/// symlinks.
+pub fn absolute(path: &str) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+/// How long restic gets to exit after SIGTERM before it is killed.
+const TERMINATE_GRACE: Duration = Duration::from_secs(30);
+
+/// Runs `restic backup` for one job against one repository, parsing
+/// restic's JSON messages as they arrive. `parent` pins the snapshot restic
+/// compares against to detect changes. If shutdown reaches `Aborting`,
+/// restic receives SIGINT so it can release its lock before exiting.
+pub async fn backup(
+    job: &BackupJobConfig,
+    config: &BackupConfig,
+    parent: Option<&str>,
+    progress: &mut ProgressTracker,
+    shutdown: &Shutdown,
+) -> Result<BackupReport, BackupError> {
+    let label = format!("{} Backup to {}", job.name, config.name);
+    let mut cmd = command(config);
+    if job.progress {
+        // restic only refreshes progress once a minute when not attached to a terminal.
+        cmd.env("RESTIC_PROGRESS_FPS", "1");
+    }
+    cmd.args(options::backup_args(job, config, parent));
+    let mut child = spawn(cmd)?;
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = tokio::spawn(read_all(child.stderr.take().unwrap()));
+    let mut output = BackupOutput::new(label.clone());
+    let mut lines = BufReader::new(stdout).lines();
+    let mut shutdown = shutdown.clone();
+    let timeout = job.timeout.map(Duration::from_secs);
+    let deadline = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+    let mut interrupted = false;
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line.map_err(BackupError::Io)? {
+                Some(line) => {
+                    if let Some(BackupMessage::Status(status)) = output.line(&line) {
+                        progress.update(&status);
+                    }
+                }
+                None => break,
+            },
+            _ = &mut deadline => {
+                let timeout = timeout.unwrap_or_default();
+                warn!("{}: restic did not finish within {}s, terminating.", label, timeout.as_secs());
+                terminate(&mut child).await;
+                return Err(BackupError::Timeout(timeout));
+            }
+            _ = shutdown.reached(Phase::Aborting), if !interrupted => {
+                warn!("{}: interrupting restic for shutdown.", label);
+                send_signal(&child, libc::SIGINT);
+                interrupted = true;
+            }
+        }
+    }
+    let status = match tokio::time::timeout(TERMINATE_GRACE, child.wait()).await {
+        Err(_) if interrupted => {
+            warn!("{}: restic ignored SIGINT for {}s, killing it.", label, TERMINATE_GRACE.as_secs());
+            let _ = child.kill().await;
+            child.wait().await
+        }
+        Err(_) => child.wait().await,
+        Ok(status) => status,
+    }
+    .map_err(BackupError::Io)?;
+    let stderr = stderr.await.unwrap_or_default();
+    if status.code() == Some(EXIT_INCOMPLETE) {
+        // The snapshot exists; the unreadable files were reported one by one.
+        return match output.finish() {
+            Ok(mut report) => {
+                report.errors = report.errors.max(1);
+                Ok(report)
+            }
+            Err(_) => Err(BackupError::Exit { code: status.code(), stderr }),
+        };
+    }
+    if !status.success() {
+        return Err(BackupError::Exit { code: status.code(), stderr });
+    }
+    if !stderr.trim().is_empty() {
+        warn!("{}: restic stderr: {}", label, stderr.trim());
+    }
+    output.finish()
+}
+
+/// Runs a restic command to completion and returns its captured output,
+/// failing if restic exits unsuccessfully.
+pub async fn output(cmd: Command) -> Result<Output, BackupError> {
+    let child = spawn(cmd)?;
+    let output = child.wait_with_output().await.map_err(BackupError::Io)?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        return Err(BackupError::Exit { code: output.status.code(), stderr });
+    }
+    Ok(output)
+}
+
+/// Like `output`, for long runs such as `check` and `prune`: if shutdown
+/// reaches `Aborting`, restic receives SIGINT so it can release its lock,
+/// and is killed if it has not exited after a grace period.
+pub async fn interruptible_output(cmd: Command, label: &str, shutdown: &Shutdown) -> Result<Output, BackupError> {
+    let mut child = spawn(cmd)?;
+    let stdout = tokio::spawn(read_all_bytes(child.stdout.take().unwrap()));
+    let stderr = tokio::spawn(read_all(child.stderr.take().unwrap()));
+    let mut shutdown = shutdown.clone();
+    let status = tokio::select! {
+        status = child.wait() => status,
+        _ = shutdown.reached(Phase::Aborting) => {
+            warn!("{}: interrupting restic for shutdown.", label);
+            send_signal(&child, libc::SIGINT);
+            match tokio::time::timeout(TERMINATE_GRACE, child.wait()).await {
+                Ok(status) => status,
+                Err(_) => {
+                    warn!("{}: restic ignored SIGINT for {}s, killing it.", label, TERMINATE_GRACE.as_secs());
+                    let _ = child.kill().await;
+                    child.wait().await
+                }
+            }
+        }
+    }
+    .map_err(BackupError::Io)?;
+    let stdout = stdout.await.unwrap_or_default();
+    let stderr = stderr.await.unwrap_or_default();
+    if !status.success() {
+        return Err(BackupError::Exit { code: status.code(), stderr });
+    }
+    Ok(Output { status, stdout, stderr: stderr.into_bytes() })
+}
+
+/// Spawns `cmd` as an async child with piped output that is killed if
+/// dropped. The child gets its own process group so a Ctrl-C aimed at the
+/// daemon doesn't interrupt restic or a hook before the shutdown grace
+/// period.
+pub fn spawn(mut cmd: Command) -> Result<Child, BackupError> {
+    cmd.process_group(0);
+    tokio::process::Command::from(cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(BackupError::Spawn)
+}
+
+fn send_signal(child: &Child, signal: libc::c_int) {
+    if let Some(pid) = child.id() {
+        // SAFETY: kill(2) has no memory-safety preconditions.
+        unsafe {
+            libc::kill(pid as libc::pid_t, signal);
+        }
+    }
+}
+
+async fn read_all_bytes(mut reader: impl AsyncRead + Unpin) -> Vec<u8> {
+    let mut buf = vec![];
+    let _ = reader.read_to_end(&mut buf).await;
+    buf
+}
+
+pub async fn read_all(mut reader: impl AsyncRead + Unpin) -> String {
+    let mut buf = String::new();
+    let _ = reader.read_to_string(&mut buf).await;
+    buf
+}
+
+/// Asks restic to stop with SIGTERM so it can clean up its lock, killing it
+/// if it is still running after a grace period.
+pub async fn terminate(child: &mut Child) {
+    send_signal(child, libc::SIGTERM);
+    if tokio::time::timeout(TERMINATE_GRACE, child.wait()).await.is_err() {
+        warn!("restic ignored SIGTERM for {}s, killing it.", TERMINATE_GRACE.as_secs());
+        let _ = child.kill().await;
+    }
+}
+
+/// Why a restic backup did not produce a usable snapshot.
+#[derive(Debug)]
+pub enum BackupError {
+    /// restic could not be started at all.
+    Spawn(std::io::Error),
+    /// Reading restic's output or waiting for it failed.
+    Io(std::io::Error),
+    /// restic exited unsuccessfully. `code` is `None` when it was killed by a signal.
+    Exit { code: Option<i32>, stderr: String },
+    /// restic did not finish within the job's timeout and was terminated.
+    Timeout(Duration),
+    /// restic succeeded but its output did not contain a summary.
+    Parse { message: String, output: String },
+    /// Shutdown started while the backup was waiting for its turn.
+    Cancelled,
+}
+
+/// restic's exit code when some source files could not be read. The
+/// snapshot is still created, without those files.
+pub const EXIT_INCOMPLETE: i32 = 3;
+
+/// restic's exit code when it could not lock the repository.
+pub const EXIT_LOCKED: i32 = 11;
+
+/// Meaning of restic's documented exit codes.
+pub fn describe_exit_code(code: Option<i32>) -> &'static str {
+    match code {
+        Some(0) => "success",
+        Some(1) => "fatal error, no snapshot created",
+        Some(EXIT_INCOMPLETE) => "some source files could not be read, snapshot was created but is incomplete",
+        Some(10) => "repository does not exist",
+        Some(EXIT_LOCKED) => "repository is locked",
+        Some(12) => "wrong password",
+        Some(130) => "interrupted",
+        Some(_) => "unknown error",
+        None => "terminated by signal",
+    }
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Spawn(e) => write!(f, "failed to spawn restic: {}", e),
+            BackupError::Io(e) => write!(f, "failed to communicate with restic: {}", e),
+            BackupError::Exit { code: Some(code), stderr } => {
+                write!(f, "restic exited with code {} ({})", code, describe_exit_code(Some(*code)))?;
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+            BackupError::Exit { code: None, stderr } => {
+                write!(f, "restic was {}", describe_exit_code(None))?;
+                if !stderr.trim().is_empty() {
+                    write!(f, ": {}", stderr.trim())?;
+                }
+                Ok(())
+            }
+            BackupError::Cancelled => write!(f, "cancelled by shutdown before restic was started"),
+            BackupError::Timeout(timeout) => write!(f, "restic did not finish within {}s and was terminated", timeout.as_secs()),
+            BackupError::Parse { message, output } if output.is_empty() => write!(f, "unable to parse restic response json: {}", message),
+            BackupError::Parse { message, output } => write!(f, "unable to parse restic response json ({}): raw response: {}", message, output),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
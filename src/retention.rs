@@ -0,0 +1,115 @@
+//! Snapshot retention: `restic forget` scoped to one job's snapshots, the
+//! same ones backups pick their parent from, optionally followed by
+//! `restic prune`.
+
+use serde::Deserialize;
+
+use crate::config::{BackupConfig, BackupJobConfig};
+use crate::restic::{self, BackupError};
+use crate::schedule::Schedule;
+use crate::shutdown::Shutdown;
+use crate::snapshots::{self, Snapshot};
+
+/// A job's `retention:` block. The `keep-*` keys map to the `restic forget`
+/// options of the same name.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Retention {
+    pub keep_last: Option<u32>,
+    pub keep_hourly: Option<u32>,
+    pub keep_daily: Option<u32>,
+    pub keep_weekly: Option<u32>,
+    pub keep_monthly: Option<u32>,
+    pub keep_yearly: Option<u32>,
+    /// restic duration such as `30d` or `1y6m`.
+    pub keep_within: Option<String>,
+    pub keep_tag: Vec<String>,
+    /// Run `restic prune` after snapshots were removed.
+    pub prune: bool,
+    /// When to apply the policy. Without one it is applied after every
+    /// successful backup.
+    pub schedule: Option<Schedule>,
+}
+
+impl Retention {
+    pub fn has_policy(&self) -> bool {
+        !self.policy_args().is_empty()
+    }
+
+    fn policy_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        let counts = [
+            ("--keep-last", self.keep_last),
+            ("--keep-hourly", self.keep_hourly),
+            ("--keep-daily", self.keep_daily),
+            ("--keep-weekly", self.keep_weekly),
+            ("--keep-monthly", self.keep_monthly),
+            ("--keep-yearly", self.keep_yearly),
+        ];
+        for (flag, value) in counts {
+            if let Some(value) = value {
+                args.push(flag.to_owned());
+                args.push(value.to_string());
+            }
+        }
+        if let Some(within) = &self.keep_within {
+            args.push("--keep-within".to_owned());
+            args.push(within.clone());
+        }
+        for tag in &self.keep_tag {
+            args.push("--keep-tag".to_owned());
+            args.push(tag.clone());
+        }
+        args
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgetGroup {
+    #[serde(default)]
+    pub keep: Option<Vec<Snapshot>>,
+    #[serde(default)]
+    pub remove: Option<Vec<Snapshot>>,
+}
+
+/// Applies the job's retention policy to one repository and prunes if
+/// configured. Returns the number of snapshots removed. restic is
+/// interrupted if shutdown reaches `Aborting`.
+pub async fn apply(job: &BackupJobConfig, config: &BackupConfig, retention: &Retention, shutdown: &Shutdown) -> Result<usize, BackupError> {
+    let label = format!("{} Retention on {}", job.name, config.name);
+    let mut cmd = restic::command(config);
+    cmd.arg("forget").arg("--json").args(snapshots::filter_args(job));
+    if !job.options.tags.is_empty() {
+        // One group across path changes, so snapshots of old paths age out too.
+        cmd.arg("--group-by").arg("host,tags");
+    }
+    cmd.args(retention.policy_args());
+    let output = restic::interruptible_output(cmd, &label, shutdown).await?;
+
+    let groups: Vec<ForgetGroup> = serde_json::from_slice(&output.stdout).map_err(|e| BackupError::Parse {
+        message: e.to_string(),
+        output: String::from_utf8_lossy(&output.stdout).into_owned(),
+    })?;
+    let mut kept = 0;
+    let mut removed = 0;
+    for group in groups {
+        for snapshot in group.keep.unwrap_or_default() {
+            kept += 1;
+            info!("{}: keeping snapshot {} from {}.", label, snapshot.short_id, snapshot.time.to_rfc3339());
+        }
+        for snapshot in group.remove.unwrap_or_default() {
+            removed += 1;
+            info!("{}: removed snapshot {} from {}.", label, snapshot.short_id, snapshot.time.to_rfc3339());
+        }
+    }
+    info!("{}: kept {} snapshot(s), removed {}.", label, kept, removed);
+
+    if retention.prune && removed > 0 {
+        info!("{}: pruning unreferenced data.", label);
+        let mut cmd = restic::command(config);
+        cmd.arg("prune");
+        restic::interruptible_output(cmd, &label, shutdown).await?;
+        info!("{}: prune complete.", label);
+    }
+    Ok(removed)
+}
@@ -0,0 +1,274 @@
+//! Retrying failed backups with exponential backoff.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::init;
+use crate::restic::{BackupError, EXIT_INCOMPLETE, EXIT_LOCKED};
+
+/// stderr fragments of Go's network and backend errors that usually clear
+/// up on their own.
+const NETWORK_ERRORS: [&str; 12] = [
+    "connection refused",
+    "connection reset",
+    "connection timed out",
+    "i/o timeout",
+    "no such host",
+    "network is unreachable",
+    "no route to host",
+    "tls handshake timeout",
+    "broken pipe",
+    "unexpected eof",
+    "service unavailable",
+    "ssh: ",
+];
+
+/// Why a backup attempt failed, as far as retrying is concerned.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureClass {
+    /// The repository was locked by another process.
+    Locked,
+    /// The repository or its backend could not be reached.
+    Network,
+    /// restic did not finish within the job's timeout.
+    Timeout,
+    /// Some files could not be read (exit code 3) and restic printed no
+    /// summary, so the snapshot cannot be confirmed.
+    Incomplete,
+    /// The repository does not exist.
+    MissingRepository,
+    WrongPassword,
+    /// restic could not be started or its output could not be read.
+    Spawn,
+    /// restic was interrupted or the backup cancelled by shutdown.
+    Interrupted,
+    /// Any other error.
+    Fatal,
+}
+
+impl FailureClass {
+    pub fn of(error: &BackupError) -> FailureClass {
+        match error {
+            BackupError::Exit { code: Some(EXIT_LOCKED), .. } => FailureClass::Locked,
+            BackupError::Exit { code: Some(EXIT_INCOMPLETE), .. } => FailureClass::Incomplete,
+            BackupError::Exit { code: Some(12), .. } => FailureClass::WrongPassword,
+            BackupError::Exit { code: Some(130), .. } | BackupError::Exit { code: None, .. } | BackupError::Cancelled => FailureClass::Interrupted,
+            e if init::is_missing(e) => FailureClass::MissingRepository,
+            BackupError::Exit { stderr, .. } => {
+                let stderr = stderr.to_lowercase();
+                if NETWORK_ERRORS.iter().any(|pattern| stderr.contains(pattern)) {
+                    FailureClass::Network
+                } else {
+                    FailureClass::Fatal
+                }
+            }
+            BackupError::Timeout(_) => FailureClass::Timeout,
+            BackupError::Spawn(_) | BackupError::Io(_) => FailureClass::Spawn,
+            BackupError::Parse { .. } => FailureClass::Fatal,
+        }
+    }
+}
+
+impl fmt::Display for FailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureClass::Locked => "locked",
+            FailureClass::Network => "network",
+            FailureClass::Timeout => "timeout",
+            FailureClass::Incomplete => "incomplete",
+            FailureClass::MissingRepository => "missing-repository",
+            FailureClass::WrongPassword => "wrong-password",
+            FailureClass::Spawn => "spawn",
+            FailureClass::Interrupted => "interrupted",
+            FailureClass::Fatal => "fatal",
+        })
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_initial_delay() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_jitter() -> f64 {
+    0.1
+}
+
+fn default_retry_on() -> Vec<FailureClass> {
+    vec![FailureClass::Locked, FailureClass::Network, FailureClass::Timeout]
+}
+
+/// A job's `retry:` block.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Wait before the second attempt, e.g. `30s`.
+    #[serde(default = "default_initial_delay", deserialize_with = "duration")]
+    pub initial_delay: Duration,
+    /// Factor the wait grows by after each further attempt.
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    /// Upper bound on the wait between attempts.
+    #[serde(default, deserialize_with = "optional_duration")]
+    pub max_delay: Option<Duration>,
+    /// Fraction of each wait that is randomized, from 0 to 1.
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+    /// Failure classes worth another attempt.
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<FailureClass>,
+}
+
+fn duration<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(d)?;
+    humantime::parse_duration(&value).map_err(|e| serde::de::Error::custom(format!("invalid duration '{}': {}", value, e)))
+}
+
+fn optional_duration<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    duration(d).map(Some)
+}
+
+impl RetryPolicy {
+    /// Problems with the policy, as (key, message) pairs.
+    pub fn problems(&self) -> Vec<(&'static str, String)> {
+        let mut problems = vec![];
+        if self.max_attempts == 0 {
+            problems.push(("max-attempts", "must be at least 1".to_owned()));
+        }
+        if self.multiplier.is_nan() || self.multiplier < 1.0 {
+            problems.push(("multiplier", "must be at least 1".to_owned()));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            problems.push(("jitter", "must be between 0 and 1".to_owned()));
+        }
+        problems
+    }
+
+    pub fn retries(&self, class: FailureClass) -> bool {
+        self.retry_on.contains(&class)
+    }
+
+    /// Wait after failed attempt number `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let base = self.initial_delay.as_secs_f64() * self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let base = match self.max_delay {
+            Some(max) => base.min(max.as_secs_f64()),
+            None => base,
+        };
+        // Spreads retries of jobs that failed together, e.g. on a network outage.
+        let spread = base * self.jitter * (2.0 * random_fraction() - 1.0);
+        Duration::from_secs_f64((base + spread).max(0.0))
+    }
+}
+
+/// A number in [0, 1) that differs between calls, which is all jitter needs.
+fn random_fraction() -> f64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    // Scramble the low bits, which a coarse clock leaves constant.
+    let mixed = (nanos as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 11;
+    mixed as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(yaml: &str) -> RetryPolicy {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn exit(code: Option<i32>, stderr: &str) -> BackupError {
+        BackupError::Exit { code, stderr: stderr.to_owned() }
+    }
+
+    #[test]
+    fn defaults() {
+        let policy = policy("{}");
+        assert_eq!(policy.max_attempts, 3);
+        assert_eq!(policy.initial_delay, Duration::from_secs(30));
+        assert_eq!(policy.retry_on, [FailureClass::Locked, FailureClass::Network, FailureClass::Timeout]);
+        assert!(policy.problems().is_empty());
+    }
+
+    #[test]
+    fn delay_grows_by_the_multiplier() {
+        let policy = policy("{initial-delay: 10s, multiplier: 3, jitter: 0}");
+        let delays: Vec<u64> = (1..=4).map(|attempt| policy.delay(attempt).as_secs()).collect();
+        assert_eq!(delays, [10, 30, 90, 270]);
+    }
+
+    #[test]
+    fn delay_is_capped_by_max_delay() {
+        let policy = policy("{initial-delay: 1m, max-delay: 5m, jitter: 0}");
+        assert_eq!(policy.delay(3), Duration::from_secs(240));
+        assert_eq!(policy.delay(4), Duration::from_secs(300));
+        assert_eq!(policy.delay(40), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let policy = policy("{initial-delay: 100s, jitter: 0.2}");
+        for _ in 0..100 {
+            let delay = policy.delay(1).as_secs_f64();
+            assert!((80.0..=120.0).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn invalid_policies_are_reported() {
+        let keys: Vec<&str> = policy("{max-attempts: 0, multiplier: 0.5, jitter: 2}").problems().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, ["max-attempts", "multiplier", "jitter"]);
+        assert!(serde_yaml::from_str::<RetryPolicy>("{retry-on: [sometimes]}").is_err());
+        assert!(serde_yaml::from_str::<RetryPolicy>("{initial-delay: soon}").is_err());
+    }
+
+    #[test]
+    fn failure_classes_from_exit_codes() {
+        assert_eq!(FailureClass::of(&exit(Some(EXIT_LOCKED), "")), FailureClass::Locked);
+        assert_eq!(FailureClass::of(&exit(Some(EXIT_INCOMPLETE), "")), FailureClass::Incomplete);
+        assert_eq!(FailureClass::of(&exit(Some(10), "")), FailureClass::MissingRepository);
+        assert_eq!(FailureClass::of(&exit(Some(12), "")), FailureClass::WrongPassword);
+        assert_eq!(FailureClass::of(&exit(Some(130), "")), FailureClass::Interrupted);
+        assert_eq!(FailureClass::of(&exit(None, "")), FailureClass::Interrupted);
+        assert_eq!(FailureClass::of(&exit(Some(1), "Fatal: invalid id")), FailureClass::Fatal);
+    }
+
+    #[test]
+    fn failure_classes_from_stderr() {
+        let network = "Fatal: unable to open repository: dial tcp 10.0.0.2:443: connect: Connection Refused";
+        assert_eq!(FailureClass::of(&exit(Some(1), network)), FailureClass::Network);
+        // restic before 0.17 reports a missing repository with exit code 1.
+        let missing = "Fatal: unable to open config file: Stat: no such file\nIs there a repository at the following location?";
+        assert_eq!(FailureClass::of(&exit(Some(1), missing)), FailureClass::MissingRepository);
+    }
+
+    #[test]
+    fn failure_classes_without_an_exit_code() {
+        assert_eq!(FailureClass::of(&BackupError::Timeout(Duration::from_secs(60))), FailureClass::Timeout);
+        assert_eq!(FailureClass::of(&BackupError::Spawn(std::io::ErrorKind::NotFound.into())), FailureClass::Spawn);
+        assert_eq!(FailureClass::of(&BackupError::Cancelled), FailureClass::Interrupted);
+        let parse = BackupError::Parse { message: "eof".to_owned(), output: String::new() };
+        assert_eq!(FailureClass::of(&parse), FailureClass::Fatal);
+    }
+
+    #[test]
+    fn only_listed_classes_are_retried() {
+        let policy = policy("{retry-on: [network, incomplete]}");
+        assert!(policy.retries(FailureClass::Network));
+        assert!(policy.retries(FailureClass::Incomplete));
+        assert!(!policy.retries(FailureClass::Locked));
+    }
+}
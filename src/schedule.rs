@@ -0,0 +1,160 @@
+//! Time-based job triggers: cron expressions or fixed intervals.
+
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::Local;
+use serde::Deserialize;
+
+use crate::job::Context;
+use crate::shutdown::Phase;
+
+/// When a job runs on a timer. Written in the config as a cron expression
+/// (`"0 3 * * *"`, with an optional leading seconds field) or as an
+/// interval (`every: 6h`).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "ScheduleSpec")]
+pub enum Schedule {
+    Cron(Box<cron::Schedule>),
+    Every(Duration),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScheduleSpec {
+    Cron(String),
+    Every(Interval),
+}
+
+/// `{every: …}`. A separate struct so that misspelled or unsupported keys
+/// such as `at` are rejected instead of ignored.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Interval {
+    every: String,
+}
+
+impl TryFrom<ScheduleSpec> for Schedule {
+    type Error = String;
+
+    fn try_from(spec: ScheduleSpec) -> Result<Schedule, String> {
+        match spec {
+            ScheduleSpec::Cron(expr) => {
+                // Standard five-field cron has no seconds; the cron crate requires them.
+                let full = if expr.split_whitespace().count() == 5 { format!("0 {}", expr) } else { expr.clone() };
+                cron::Schedule::from_str(&full)
+                    .map(|s| Schedule::Cron(Box::new(s)))
+                    .map_err(|e| format!("invalid cron expression '{}': {}", expr, e))
+            }
+            ScheduleSpec::Every(Interval { every }) => match humantime::parse_duration(&every) {
+                Ok(d) if d.as_secs() > 0 => Ok(Schedule::Every(d)),
+                Ok(_) => Err("interval must be at least one second".to_owned()),
+                Err(e) => Err(format!("invalid interval '{}': {}", every, e)),
+            },
+        }
+    }
+}
+
+impl Schedule {
+    /// Time from now until the next run, or `None` if it never runs again.
+    pub fn next_delay(&self) -> Option<Duration> {
+        match self {
+            Schedule::Every(interval) => Some(*interval),
+            Schedule::Cron(cron) => {
+                let now = Local::now();
+                let next = cron.after(&now).next()?;
+                Some((next - now).to_std().unwrap_or_default())
+            }
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Schedule::Every(interval) => format!("every {}", humantime::format_duration(*interval)),
+            Schedule::Cron(cron) => format!("cron '{}'", cron),
+        }
+    }
+}
+
+/// Runs `task` on the schedule until shutdown starts. `what` names the task
+/// in log lines.
+pub async fn run_schedule<F, Fut>(what: String, schedule: Schedule, ctx: Context, mut task: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    info!("Scheduled {} - {}", what, schedule.describe());
+    let mut shutdown = ctx.shutdown.clone();
+    loop {
+        let delay = match schedule.next_delay() {
+            Some(delay) => delay,
+            None => {
+                info!("Schedule for {} has no further runs.", what);
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = shutdown.reached(Phase::Stopping) => return,
+        }
+        task().await;
+        if shutdown.phase() != Phase::Running {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Result<Schedule, String> {
+        serde_yaml::from_str::<Schedule>(yaml).map_err(|e| e.to_string())
+    }
+
+    fn cron(yaml: &str) -> cron::Schedule {
+        match parse(yaml).unwrap() {
+            Schedule::Cron(cron) => *cron,
+            other => panic!("expected a cron schedule, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn five_field_cron_runs_at_second_zero() {
+        let five = cron("'30 3 * * *'");
+        assert_eq!(five.to_string(), "0 30 3 * * *");
+        let next = five.upcoming(Local).next().unwrap();
+        assert_eq!(next.format("%H:%M:%S").to_string(), "03:30:00");
+    }
+
+    #[test]
+    fn six_field_cron_is_used_as_is() {
+        assert_eq!(cron("'15 */5 * * * *'").to_string(), "15 */5 * * * *");
+    }
+
+    #[test]
+    fn invalid_cron_names_the_expression() {
+        let error = parse("'61 * * * *'").unwrap_err();
+        assert!(error.contains("invalid cron expression '61 * * * *'"), "{}", error);
+    }
+
+    #[test]
+    fn intervals() {
+        assert!(matches!(parse("{every: 6h}"), Ok(Schedule::Every(d)) if d == Duration::from_secs(6 * 3600)));
+        assert!(parse("{every: 0s}").unwrap_err().contains("at least one second"));
+        assert!(parse("{every: often}").unwrap_err().contains("invalid interval 'often'"));
+    }
+
+    #[test]
+    fn intervals_reject_unknown_keys() {
+        assert!(parse("{every: 6h, at: '03:00'}").is_err());
+    }
+
+    #[test]
+    fn next_delay_of_an_interval_is_the_interval() {
+        let schedule = Schedule::Every(Duration::from_secs(90));
+        assert_eq!(schedule.next_delay(), Some(Duration::from_secs(90)));
+        assert_eq!(schedule.describe(), "every 1m 30s");
+    }
+}
@@ -0,0 +1,53 @@
+//! Admission of restic processes: a queue per repository limiting how many
+//! backups run against it at once, and a daemon-wide limit on the number of
+//! restic processes doing heavy work.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::BackupConfig;
+
+pub struct Scheduler {
+    repos: HashMap<String, Arc<Semaphore>>,
+    processes: Option<Arc<Semaphore>>,
+}
+
+impl Scheduler {
+    /// `max_processes` limits concurrent backup, check, forget and prune
+    /// runs across all repositories; `None` means no limit. Each of
+    /// `repositories` gets a backup queue sized by its `max-parallel-backups`,
+    /// which config loading ensures all targets of a repository agree on.
+    pub fn new(max_processes: Option<usize>, repositories: &[&BackupConfig]) -> Arc<Scheduler> {
+        let repos = repositories.iter().map(|r| (r.repo.clone(), Arc::new(Semaphore::new(r.max_parallel_backups)))).collect();
+        Arc::new(Scheduler { repos, processes: max_processes.map(|n| Arc::new(Semaphore::new(n))) })
+    }
+
+    /// Waits for the repository's backup queue to admit `label`.
+    pub async fn backup_slot(&self, label: &str, config: &BackupConfig) -> OwnedSemaphorePermit {
+        // Every target's repository is among those the scheduler was built with.
+        let queue = self.repos[&config.repo].clone();
+        acquire(queue, || {
+            info!("{} queued, {} already has {} backup(s) running.", label, config.name, config.max_parallel_backups);
+        })
+        .await
+    }
+
+    /// Waits until the daemon-wide process limit allows another restic run.
+    pub async fn process_slot(&self, label: &str) -> Option<OwnedSemaphorePermit> {
+        let processes = self.processes.clone()?;
+        Some(acquire(processes, || info!("{} queued until another restic process finishes.", label)).await)
+    }
+}
+
+async fn acquire(semaphore: Arc<Semaphore>, on_wait: impl FnOnce()) -> OwnedSemaphorePermit {
+    match semaphore.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            on_wait();
+            // The semaphores are never closed.
+            semaphore.acquire_owned().await.unwrap()
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Repository passwords and backend credentials, and how they reach restic.
+//!
+//! Secrets are only ever passed to restic through its environment. Their
+//! `Debug` output is redacted so they cannot end up in the log, and files
+//! holding them are refused when other users can read them.
+
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+
+use serde::Deserialize;
+use serde_yaml::Value;
+
+/// Password variables restic reads, cleared so that nothing inherited from
+/// the daemon's environment competes with the configured source.
+const PASSWORD_VARS: [&str; 3] = ["RESTIC_PASSWORD", "RESTIC_PASSWORD_FILE", "RESTIC_PASSWORD_COMMAND"];
+
+/// A secret value, given inline, as `{file: path}` or as `{env: VARIABLE}`.
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "Value")]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl TryFrom<Value> for Secret {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Secret, String> {
+        const EXPECTED: &str = "expected a string, {file: path} or {env: VARIABLE}";
+        let map = match value {
+            Value::String(s) => return Ok(Secret(s)),
+            Value::Mapping(map) if map.len() == 1 => map,
+            _ => return Err(EXPECTED.to_owned()),
+        };
+        match map.into_iter().next() {
+            Some((Value::String(key), Value::String(file))) if key == "file" => read_secret_file(&file).map(Secret),
+            Some((Value::String(key), Value::String(var))) if key == "env" => {
+                std::env::var(&var).map(Secret).map_err(|_| format!("environment variable {} is not set", var))
+            }
+            _ => Err(EXPECTED.to_owned()),
+        }
+    }
+}
+
+/// Fails if the file can be read by users other than its owner and group.
+pub fn check_permissions(path: &str) -> Result<(), String> {
+    let meta = std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?;
+    if meta.permissions().mode() & 0o004 != 0 {
+        return Err(format!("{} is readable by other users, restrict it with chmod o-rwx", path));
+    }
+    Ok(())
+}
+
+fn read_secret_file(path: &str) -> Result<String, String> {
+    check_permissions(path)?;
+    let content = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(content.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Where restic gets the repository password from.
+#[derive(Clone, Debug)]
+pub enum PasswordSource {
+    /// Shell command printing the password.
+    Command(String),
+    /// File holding the password, read by restic itself.
+    File(String),
+    /// Environment variable of the daemon holding the password.
+    Env(String),
+}
+
+impl PasswordSource {
+    /// Sets the password on a restic command.
+    pub fn apply(&self, cmd: &mut Command) {
+        for var in PASSWORD_VARS {
+            cmd.env_remove(var);
+        }
+        match self {
+            PasswordSource::Command(command) => cmd.env("RESTIC_PASSWORD_COMMAND", command),
+            PasswordSource::File(file) => cmd.env("RESTIC_PASSWORD_FILE", file),
+            PasswordSource::Env(var) => cmd.env("RESTIC_PASSWORD", std::env::var(var).unwrap_or_default()),
+        };
+    }
+
+    /// Passes the password of the repository given with `--from-repo`.
+    pub fn apply_from(&self, cmd: &mut Command) {
+        match self {
+            PasswordSource::Command(command) => cmd.arg("--from-password-command").arg(command),
+            PasswordSource::File(file) => cmd.arg("--from-password-file").arg(file),
+            PasswordSource::Env(var) => cmd.env("RESTIC_FROM_PASSWORD", std::env::var(var).unwrap_or_default()),
+        };
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AwsCredentials {
+    pub access_key_id: Option<Secret>,
+    pub secret_access_key: Option<Secret>,
+    pub session_token: Option<Secret>,
+    pub default_region: Option<String>,
+    pub profile: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct B2Credentials {
+    pub account_id: Option<Secret>,
+    pub account_key: Option<Secret>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AzureCredentials {
+    pub account_name: Option<Secret>,
+    pub account_key: Option<Secret>,
+    pub account_sas: Option<Secret>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RestCredentials {
+    pub username: Option<Secret>,
+    pub password: Option<Secret>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SftpSettings {
+    /// Command restic runs instead of `ssh` to reach the server.
+    pub command: Option<String>,
+}
+
+/// A `credentials:` block. Each backend section is inherited as a whole
+/// from the next level up when it is not set.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Credentials {
+    pub aws: Option<AwsCredentials>,
+    pub b2: Option<B2Credentials>,
+    pub azure: Option<AzureCredentials>,
+    pub rest: Option<RestCredentials>,
+    pub sftp: Option<SftpSettings>,
+}
+
+impl Credentials {
+    pub fn or(self, fallback: Credentials) -> Credentials {
+        Credentials {
+            aws: self.aws.or(fallback.aws),
+            b2: self.b2.or(fallback.b2),
+            azure: self.azure.or(fallback.azure),
+            rest: self.rest.or(fallback.rest),
+            sftp: self.sftp.or(fallback.sftp),
+        }
+    }
+
+    /// Injects the credentials into a restic command.
+    pub fn apply(&self, cmd: &mut Command) {
+        let mut secrets: Vec<(&str, &Option<Secret>)> = vec![];
+        if let Some(aws) = &self.aws {
+            secrets.push(("AWS_ACCESS_KEY_ID", &aws.access_key_id));
+            secrets.push(("AWS_SECRET_ACCESS_KEY", &aws.secret_access_key));
+            secrets.push(("AWS_SESSION_TOKEN", &aws.session_token));
+            if let Some(region) = &aws.default_region {
+                cmd.env("AWS_DEFAULT_REGION", region);
+            }
+            if let Some(profile) = &aws.profile {
+                cmd.env("AWS_PROFILE", profile);
+            }
+        }
+        if let Some(b2) = &self.b2 {
+            secrets.push(("B2_ACCOUNT_ID", &b2.account_id));
+            secrets.push(("B2_ACCOUNT_KEY", &b2.account_key));
+        }
+        if let Some(azure) = &self.azure {
+            secrets.push(("AZURE_ACCOUNT_NAME", &azure.account_name));
+            secrets.push(("AZURE_ACCOUNT_KEY", &azure.account_key));
+            secrets.push(("AZURE_ACCOUNT_SAS", &azure.account_sas));
+        }
+        if let Some(rest) = &self.rest {
+            secrets.push(("RESTIC_REST_USERNAME", &rest.username));
+            secrets.push(("RESTIC_REST_PASSWORD", &rest.password));
+        }
+        for (var, secret) in secrets {
+            if let Some(secret) = secret {
+                cmd.env(var, secret.expose());
+            }
+        }
+        if let Some(command) = self.sftp.as_ref().and_then(|s| s.command.as_ref()) {
+            cmd.arg("-o").arg(format!("sftp.command={}", command));
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Coordinated shutdown on SIGINT/SIGTERM.
+//!
+//! Shutdown happens in two phases: `Stopping` stops watchers from starting
+//! new backups while running ones continue, and `Aborting` (once the grace
+//! period expires or a second signal arrives) interrupts restic so it can
+//! clean up before exiting.
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    Stopping,
+    Aborting,
+}
+
+/// Handle used by watchers and restic runs to observe the shutdown phase.
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<Phase>,
+}
+
+pub struct Trigger {
+    tx: watch::Sender<Phase>,
+}
+
+pub fn channel() -> (Trigger, Shutdown) {
+    let (tx, rx) = watch::channel(Phase::Running);
+    (Trigger { tx }, Shutdown { rx })
+}
+
+impl Trigger {
+    pub fn set(&self, phase: Phase) {
+        self.tx.send_if_modified(|current| {
+            let advance = phase > *current;
+            if advance {
+                *current = phase;
+            }
+            advance
+        });
+    }
+}
+
+impl Shutdown {
+    pub fn phase(&self) -> Phase {
+        *self.rx.borrow()
+    }
+
+    /// Completes once shutdown has reached `phase`. Never completes if the
+    /// trigger is dropped first.
+    pub async fn reached(&mut self, phase: Phase) {
+        if self.rx.wait_for(|current| *current >= phase).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Waits for SIGINT or SIGTERM and returns its name.
+pub async fn signal_received() -> &'static str {
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler.");
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler.");
+    tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
@@ -0,0 +1,45 @@
+//! Lookup of a job's existing snapshots.
+
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+
+use crate::config::{BackupConfig, BackupJobConfig};
+use crate::restic::{self, BackupError};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Snapshot {
+    pub time: DateTime<FixedOffset>,
+    pub id: String,
+    #[serde(default)]
+    pub short_id: String,
+}
+
+/// restic options selecting the job's snapshots. Snapshots are matched by
+/// the job's host and tags so that changing its paths keeps the history;
+/// jobs without tags are matched by their paths instead.
+pub fn filter_args(job: &BackupJobConfig) -> Vec<String> {
+    let mut args = vec!["--host".to_owned(), job.options.snapshot_host()];
+    if job.options.tags.is_empty() {
+        for path in &job.paths {
+            args.push("--path".to_owned());
+            args.push(restic::absolute(path).display().to_string());
+        }
+    } else {
+        args.push("--tag".to_owned());
+        args.push(job.options.tags.join(","));
+    }
+    args
+}
+
+/// The job's most recent snapshot in the repository. restic returns the
+/// latest snapshot of each path set, of which the newest wins.
+pub async fn latest(job: &BackupJobConfig, config: &BackupConfig) -> Result<Option<Snapshot>, BackupError> {
+    let mut cmd = restic::command(config);
+    cmd.arg("--no-lock").arg("snapshots").arg("--json").arg("--latest").arg("1").args(filter_args(job));
+    let output = restic::output(cmd).await?;
+    let snapshots: Vec<Snapshot> = serde_json::from_slice(&output.stdout).map_err(|e| BackupError::Parse {
+        message: e.to_string(),
+        output: String::from_utf8_lossy(&output.stdout).into_owned(),
+    })?;
+    Ok(snapshots.into_iter().max_by_key(|s| s.time))
+}
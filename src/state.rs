@@ -0,0 +1,209 @@
+//! State that survives restarts: when each job last ran and succeeded, its
+//! last snapshot and failure streak per repository, and the last check and
+//! unlock of each repository.
+//!
+//! With `state-dir` configured, every job and repository has its own JSON
+//! file there, replaced atomically on each update. A missing or corrupt file
+//! only loses history: callers fall back to asking restic.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::config::BackupJobConfig;
+use crate::messages::BackupReport;
+use crate::restic::BackupError;
+use crate::status::{format_duration, unix_now};
+
+/// A job's state in one repository. Times are Unix seconds.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TargetState {
+    pub last_run: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_snapshot: Option<String>,
+    /// When the run that created `last_snapshot` started.
+    pub last_snapshot_time: Option<u64>,
+    /// The last error, cleared by a successful backup.
+    pub last_error: Option<String>,
+    /// Failed backups since the last successful one.
+    pub failure_streak: u32,
+    pub last_forget: Option<u64>,
+    pub last_forget_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct JobState {
+    /// Keyed by repository name.
+    pub targets: BTreeMap<String, TargetState>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RepositoryState {
+    pub last_check: Option<u64>,
+    pub last_check_error: Option<String>,
+    /// Next `--read-data-subset` index, counting from 0.
+    pub next_check_subset: u32,
+    pub last_unlock: Option<u64>,
+    pub locks_removed: usize,
+}
+
+pub struct StateStore {
+    dir: Option<PathBuf>,
+    jobs: Mutex<HashMap<String, JobState>>,
+    repositories: Mutex<HashMap<String, RepositoryState>>,
+}
+
+impl StateStore {
+    /// Without a directory the state is kept in memory only.
+    pub fn open(dir: Option<String>) -> Arc<StateStore> {
+        let dir = dir.map(PathBuf::from);
+        if let Some(dir) = &dir {
+            for sub in ["jobs", "repositories"] {
+                if let Err(e) = std::fs::create_dir_all(dir.join(sub)) {
+                    warn!("Unable to create state directory {}: {}", dir.join(sub).display(), e);
+                }
+            }
+        }
+        Arc::new(StateStore { dir, jobs: Mutex::new(HashMap::new()), repositories: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn job(&self, job: &str) -> JobState {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.entry(&mut jobs, "jobs", job).clone()
+    }
+
+    pub fn repository(&self, repo: &str) -> RepositoryState {
+        let mut repos = self.repositories.lock().unwrap();
+        self.entry(&mut repos, "repositories", repo).clone()
+    }
+
+    /// Every job loaded so far, for the status file.
+    pub fn jobs(&self) -> BTreeMap<String, JobState> {
+        self.jobs.lock().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+
+    /// Records a backup to one repository that started at `started`.
+    pub fn record_backup(&self, job: &str, target: &str, started: u64, result: &Result<BackupReport, BackupError>) {
+        self.update_target(job, target, |state| {
+            state.last_run = Some(unix_now());
+            match result {
+                Ok(report) => {
+                    state.last_success = state.last_run;
+                    state.last_snapshot = report.summary.snapshot_id.clone().or(state.last_snapshot.take());
+                    state.last_snapshot_time = Some(started);
+                    state.last_error = None;
+                    state.failure_streak = 0;
+                }
+                Err(e) => {
+                    state.last_error = Some(e.to_string());
+                    state.failure_streak += 1;
+                }
+            }
+        });
+    }
+
+    pub fn record_forget(&self, job: &str, target: &str, result: &Result<usize, BackupError>) {
+        self.update_target(job, target, |state| {
+            state.last_forget = Some(unix_now());
+            state.last_forget_error = result.as_ref().err().map(ToString::to_string);
+        });
+    }
+
+    pub fn record_check(&self, repo: &str, next_subset: u32, result: &Result<(), BackupError>) {
+        self.update_repository(repo, |state| {
+            state.last_check = Some(unix_now());
+            state.last_check_error = result.as_ref().err().map(ToString::to_string);
+            state.next_check_subset = next_subset;
+        });
+    }
+
+    pub fn record_unlock(&self, repo: &str, removed: usize) {
+        self.update_repository(repo, |state| {
+            state.last_unlock = Some(unix_now());
+            state.locks_removed += removed;
+        });
+    }
+
+    /// Logs what is known about the job's previous runs.
+    pub fn report(&self, job: &BackupJobConfig) {
+        let state = self.job(&job.name);
+        for target in &job.targets {
+            let Some(t) = state.targets.get(&target.name) else { continue };
+            if t.failure_streak > 0 {
+                warn!(
+                    "{} Backup to {} failed the last {} time(s): {}",
+                    job.name,
+                    target.name,
+                    t.failure_streak,
+                    t.last_error.as_deref().unwrap_or("unknown error")
+                );
+            } else if let Some(success) = t.last_success {
+                info!(
+                    "{} Backup to {} last succeeded {} ago, snapshot {}.",
+                    job.name,
+                    target.name,
+                    format_duration(unix_now().saturating_sub(success)),
+                    t.last_snapshot.as_deref().unwrap_or("unknown")
+                );
+            }
+        }
+    }
+
+    fn update_target(&self, job: &str, target: &str, update: impl FnOnce(&mut TargetState)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let state = self.entry(&mut jobs, "jobs", job);
+        update(state.targets.entry(target.to_owned()).or_default());
+        self.save("jobs", job, state);
+    }
+
+    fn update_repository(&self, repo: &str, update: impl FnOnce(&mut RepositoryState)) {
+        let mut repos = self.repositories.lock().unwrap();
+        let state = self.entry(&mut repos, "repositories", repo);
+        update(state);
+        self.save("repositories", repo, state);
+    }
+
+    /// The cached state for `name`, loaded from disk on first use.
+    fn entry<'a, T: DeserializeOwned + Default>(&self, cache: &'a mut HashMap<String, T>, kind: &str, name: &str) -> &'a mut T {
+        cache.entry(name.to_owned()).or_insert_with(|| self.load(kind, name))
+    }
+
+    fn path(&self, kind: &str, name: &str) -> Option<PathBuf> {
+        let file: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' }).collect();
+        Some(self.dir.as_ref()?.join(kind).join(format!("{}.json", file)))
+    }
+
+    fn load<T: DeserializeOwned + Default>(&self, kind: &str, name: &str) -> T {
+        let Some(path) = self.path(kind, name) else { return T::default() };
+        let text = match std::fs::read(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return T::default(),
+            Err(e) => {
+                warn!("Unable to read state file {}: {}", path.display(), e);
+                return T::default();
+            }
+        };
+        serde_json::from_slice(&text).unwrap_or_else(|e| {
+            warn!("State file {} is corrupt and will be replaced: {}", path.display(), e);
+            T::default()
+        })
+    }
+
+    fn save<T: Serialize>(&self, kind: &str, name: &str, state: &T) {
+        let Some(path) = self.path(kind, name) else { return };
+        let tmp = path.with_extension("json.tmp");
+        let result = serde_json::to_vec_pretty(state)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&tmp, json))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            warn!("Unable to write state file {}: {}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Live progress of running backups. Progress is logged periodically and,
+//! when `status-file` is configured, mirrored to a JSON file that external
+//! tools can poll, along with each job's state from previous runs.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::config::{BackupConfig, BackupJobConfig};
+use crate::messages::Status;
+use crate::state::{JobState, StateStore};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RunningBackup {
+    pub job: String,
+    pub repository: String,
+    pub paths: Vec<String>,
+    /// Unix time the backup started.
+    pub started: u64,
+    /// Latest status message from restic, if progress reporting is enabled.
+    pub progress: Option<Status>,
+}
+
+#[derive(Serialize)]
+struct StatusFile<'a> {
+    updated: u64,
+    running: Vec<&'a RunningBackup>,
+    jobs: BTreeMap<String, JobState>,
+}
+
+pub struct StatusBoard {
+    file: Option<String>,
+    state: Arc<StateStore>,
+    running: Mutex<BTreeMap<String, RunningBackup>>,
+}
+
+impl StatusBoard {
+    pub fn new(file: Option<String>, state: Arc<StateStore>) -> Arc<StatusBoard> {
+        let board = Arc::new(StatusBoard { file, state, running: Mutex::new(BTreeMap::new()) });
+        board.write(&board.running.lock().unwrap());
+        board
+    }
+
+    /// Registers a backup as running until the returned tracker is dropped.
+    pub fn track(self: &Arc<Self>, job: &BackupJobConfig, target: &BackupConfig) -> ProgressTracker {
+        let key = format!("{}/{}", job.name, target.name);
+        let entry = RunningBackup {
+            job: job.name.clone(),
+            repository: target.name.clone(),
+            paths: job.paths.clone(),
+            started: unix_now(),
+            progress: None,
+        };
+        let mut running = self.running.lock().unwrap();
+        running.insert(key.clone(), entry);
+        self.write(&running);
+        ProgressTracker {
+            board: self.clone(),
+            key,
+            label: format!("{} Backup to {}", job.name, target.name),
+            interval: Duration::from_secs(job.progress_interval),
+            last_log: Instant::now(),
+        }
+    }
+
+    /// Labels of the backups currently running, as `job/repository`.
+    pub fn running(&self) -> Vec<String> {
+        self.running.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Rewrites the status file, e.g. after the job state changed.
+    pub fn refresh(&self) {
+        self.write(&self.running.lock().unwrap());
+    }
+
+    fn write(&self, running: &BTreeMap<String, RunningBackup>) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let status = StatusFile { updated: unix_now(), running: running.values().collect(), jobs: self.state.jobs() };
+        let tmp = format!("{}.tmp", file);
+        let result = serde_json::to_vec_pretty(&status)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&tmp, json))
+            .and_then(|_| std::fs::rename(&tmp, file));
+        if let Err(e) = result {
+            warn!("Unable to write status file {}: {}", file, e);
+        }
+    }
+}
+
+pub struct ProgressTracker {
+    board: Arc<StatusBoard>,
+    key: String,
+    label: String,
+    interval: Duration,
+    last_log: Instant,
+}
+
+impl ProgressTracker {
+    /// Records a restic status message, logging it and rewriting the status
+    /// file at most once per interval.
+    pub fn update(&mut self, status: &Status) {
+        let mut running = self.board.running.lock().unwrap();
+        if let Some(entry) = running.get_mut(&self.key) {
+            entry.progress = Some(status.clone());
+        }
+        if self.last_log.elapsed() < self.interval {
+            return;
+        }
+        self.board.write(&running);
+        drop(running);
+        self.last_log = Instant::now();
+        info!(
+            "{}: {:.1}% done, {}/{} files, {}/{}, {} remaining{}",
+            self.label,
+            status.percent_done * 100.0,
+            status.files_done,
+            status.total_files,
+            format_bytes(status.bytes_done),
+            format_bytes(status.total_bytes),
+            format_duration(status.seconds_remaining),
+            match status.current_files.first() {
+                Some(file) => format!(", current: {}", file),
+                None => String::new(),
+            }
+        );
+    }
+}
+
+impl Drop for ProgressTracker {
+    fn drop(&mut self) {
+        let mut running = self.board.running.lock().unwrap();
+        running.remove(&self.key);
+        self.board.write(&running);
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn format_duration(seconds: u64) -> String {
+    match seconds {
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
@@ -0,0 +1,104 @@
+//! Filesystem watching and debouncing of change events into backups.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::Instant;
+
+use crate::config::BackupJobConfig;
+use crate::job::{Context, Job, Trigger};
+use crate::shutdown::Phase;
+
+/// Watches the job's paths and backs them up once changes settle.
+///
+/// A backup starts after `throttle` seconds without further events, or
+/// `max-wait` seconds after the first event if changes keep arriving. Events
+/// seen while a backup is running mark the job dirty, and a dirty job gets
+/// exactly one follow-up backup. Returns once shutdown starts and no backup
+/// is running.
+pub async fn start_watching(handle: Arc<Job>, ctx: Context) {
+    let job = &handle.config;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        // restic reading files must not look like a change.
+        if let Ok(event) = res {
+            if !matches!(event.kind, EventKind::Access(_)) {
+                let _ = tx.send(());
+            }
+        }
+    })
+    .unwrap();
+    for path in &job.paths {
+        watcher
+            .watch(Path::new(path), RecursiveMode::Recursive)
+            .unwrap_or_else(|_| panic!("Failed to start watching on path {}", path));
+    }
+    info!(
+        "Started FSEvent monitoring on {} named {} - interval={} max-wait={}",
+        job.paths_label(), &job.name, &job.throttle, &job.max_wait
+    );
+
+    let mut shutdown = ctx.shutdown.clone();
+    let mut dirty = false;
+    loop {
+        if !dirty {
+            tokio::select! {
+                event = rx.recv() => if event.is_none() { return },
+                _ = shutdown.reached(Phase::Stopping) => return,
+            }
+        }
+        if dirty {
+            info!("{} changed during the last backup, scheduling a follow-up.", job.name);
+        } else {
+            info!("FS Changes detected on {}, backup scheduled after {} quiet seconds.", job.paths_label(), job.throttle);
+        }
+        tokio::select! {
+            _ = settle(job, &mut rx) => {},
+            _ = shutdown.reached(Phase::Stopping) => {
+                info!("{} has pending changes that will not be backed up due to shutdown.", job.name);
+                return;
+            }
+        }
+
+        dirty = false;
+        let backup = handle.run(Trigger::Changes, &ctx);
+        tokio::pin!(backup);
+        loop {
+            tokio::select! {
+                _ = &mut backup => break,
+                Some(()) = rx.recv() => dirty = true,
+            }
+        }
+        if shutdown.phase() != Phase::Running {
+            if dirty {
+                info!("{} has pending changes that will not be backed up due to shutdown.", job.name);
+            }
+            return;
+        }
+    }
+}
+
+/// Waits until no event has arrived for `throttle` seconds, or until
+/// `max-wait` seconds have passed.
+async fn settle(job: &BackupJobConfig, rx: &mut UnboundedReceiver<()>) {
+    let quiet = Duration::from_secs(job.throttle);
+    let cap = Instant::now() + Duration::from_secs(job.max_wait);
+    let mut deadline = (Instant::now() + quiet).min(cap);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => {
+                if deadline == cap {
+                    info!("{} is still changing after {} seconds, backing up anyway.", job.name, job.max_wait);
+                }
+                return;
+            }
+            event = rx.recv() => match event {
+                Some(()) => deadline = (Instant::now() + quiet).min(cap),
+                None => return,
+            },
+        }
+    }
+}